@@ -1,10 +1,8 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::path::Path;
 use std::process::{Command, ExitStatus};
-
-use chrono;
+use std::time::SystemTime;
 
 // Get path relative to the crate root.
 // Taken from Rocket's rocket::fs::relative.
@@ -19,59 +17,188 @@ macro_rules! relative {
     };
 }
 
+// Inputs of the Svelte build, relative to the crate root
+const FRONTEND_INPUTS: [&str; 11] = [
+    "index.html",
+    "package.json",
+    "package-lock.json",
+    "vite.config.ts",
+    "svelte.config.js",
+    "tailwind.config.ts",
+    "postcss.config.js",
+    "tsconfig.json",
+    "tsconfig.node.json",
+    "src/**/*.svelte",
+    "src/**/*.{ts,css}",
+];
+
+// Set to anything but "" or "0" to skip a step regardless of features
+fn skip(var: &str) -> bool {
+    println!("cargo::rerun-if-env-changed={}", var);
+    std::env::var(var).is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+// Files matching `patterns` (relative to the crate root), which cargo is
+// also told to watch
+fn watch(patterns: &[&str]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    for pattern in patterns {
+        // glob has no {a,b} alternation
+        let expanded: Vec<String> = match pattern.split_once('{') {
+            Some((pre, rest)) => {
+                let (alts, post) = rest
+                    .split_once('}')
+                    .ok_or_else(|| format!("unclosed '{{' in watch pattern {:?}", pattern))?;
+                alts.split(',').map(|a| [pre, a, post].concat()).collect()
+            }
+            None => vec![pattern.to_string()],
+        };
+        for p in expanded {
+            for entry in glob::glob(&[env!("CARGO_MANIFEST_DIR"), "/", &p].concat())? {
+                let path = entry?.to_string_lossy().into_owned();
+                println!("cargo::rerun-if-changed={}", path);
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Whether `output` is missing or older than any of `inputs`
+fn stale(output: &str, inputs: &[String]) -> bool {
+    let Some(built) = mtime(output) else {
+        return true;
+    };
+    inputs.iter().any(|i| mtime(i).is_none_or(|t| t > built))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("cargo::rerun-if-changed=NEVER_EXISTING_FILE");
-
-    // install dependencies
-    Command::new("npm").arg("i").status()?.eok()?;
-
-    // Build Svelte
-    Command::new("npm").arg("run").arg("build").status()?.eok()?;
-
-    // Dynamically regenerate the Migrator
-    // TODO: make a script for automatically generating the mRS files
-    let mut f = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(relative!("src/migrator/mod.rs"))?;
-    f.write_all(
-        format!(
-            "/* Auto-generated at {:?} */\nuse sea_orm_migration::prelude::*;\npub struct Migrator;\n",
-            chrono::offset::Local::now()
-        )
-        .as_bytes(),
-    )?;
-    let files: Vec<String> = glob::glob(relative!("src/migrator/m*.rs"))?
-        .filter_map(|e| match e {
-            Ok(v) => match v.into_os_string().into_string() {
-                Ok(s) => {
-                    if !s.ends_with("/mod.rs") {
-                        let v = s.rsplit_once("/").unwrap().1;
-                        Some(v[..v.len() - 3].to_owned())
-                    } else {
-                        None
-                    }
-                }
-                Err(e) => panic!("{:?} is an invalid path", e),
-            },
-            Err(e) => panic!("{:?}", e),
+    println!("cargo::rerun-if-changed=build.rs");
+
+    // Reported by /api/version
+    for p in [".git/HEAD", ".git/logs/HEAD"] {
+        if Path::new(relative!("")).join(p).exists() {
+            println!("cargo::rerun-if-changed={}", p);
+        }
+    }
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo::rustc-env=FRQ_GIT_HASH={}", git_hash);
+
+    build_frontend()?;
+    let migrations = write_migrator()?;
+    generate_entities(&migrations).await?;
+    Ok(())
+}
+
+// Svelte -> dist/, unless it is already up to date. Off with
+// --no-default-features (without `frontend`) or FRQ_SKIP_FRONTEND=1,
+// e.g. when dist/ comes from elsewhere (see FRQ_BUILD_DIST).
+fn build_frontend() -> Result<(), Box<dyn std::error::Error>> {
+    if cfg!(not(feature = "frontend")) || skip("FRQ_SKIP_FRONTEND") {
+        if !Path::new(relative!("dist/index.html")).exists() {
+            println!("cargo::warning=frontend build skipped and there is no dist/index.html");
+        }
+        return Ok(());
+    }
+    let inputs = watch(&FRONTEND_INPUTS)?;
+    if !stale(relative!("dist/index.html"), &inputs) {
+        return Ok(());
+    }
+    // npm ci installs exactly what package-lock.json says; only needed
+    // when node_modules is missing or older than the lockfile
+    if stale(
+        relative!("node_modules/.package-lock.json"),
+        &[relative!("package-lock.json").to_owned()],
+    ) {
+        Command::new("npm")
+            .arg("ci")
+            .current_dir(relative!(""))
+            .status()?
+            .eok()?;
+    }
+    Command::new("npm")
+        .args(["run", "build"])
+        .current_dir(relative!(""))
+        .status()?
+        .eok()?;
+    Ok(())
+}
+
+// Regenerates src/migrator/mod.rs from the migration files. The output
+// only depends on the file names, and is left untouched if unchanged.
+fn write_migrator() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // The directory too, so added or removed migrations are noticed
+    println!("cargo::rerun-if-changed=src/migrator");
+    // New migration files come from `fastrequest migrate new <name>`
+    let paths = watch(&["src/migrator/m*.rs"])?;
+    let mut files: Vec<String> = paths
+        .iter()
+        .filter(|s| !s.ends_with("/mod.rs"))
+        .map(|s| {
+            let v = s.rsplit_once('/').unwrap().1;
+            v[..v.len() - 3].to_owned()
         })
         .collect();
+    files.sort();
+
+    let mut out = String::from(
+        "/* Generated by build.rs from the files in this directory; do not edit */\n\
+         use sea_orm_migration::prelude::*;\npub struct Migrator;\n",
+    );
     for k in &files {
-        f.write_all(format!("mod {};\n", k).as_bytes())?;
+        out.push_str(&format!("mod {};\n", k));
     }
-    f.write_all(b"#[async_trait::async_trait]\nimpl MigratorTrait for Migrator {\n    fn migrations() -> Vec<Box<dyn MigrationTrait>> {\n        vec![\n")?;
+    out.push_str("#[async_trait::async_trait]\nimpl MigratorTrait for Migrator {\n    fn migrations() -> Vec<Box<dyn MigrationTrait>> {\n        vec![\n");
     for k in &files {
-        f.write_all(format!("            Box::new({}::Migration),\n", k).as_bytes())?;
+        out.push_str(&format!("            Box::new({}::Migration),\n", k));
     }
-    f.write_all(b"        ]\n    }\n}")?;
-    f.flush()?;
+    out.push_str("        ]\n    }\n}");
 
-    Command::new("cargo")
-        .arg("run")
-        .arg("--release")
+    let target = relative!("src/migrator/mod.rs");
+    if std::fs::read_to_string(target).ok().as_deref() != Some(out.as_str()) {
+        std::fs::write(target, out)?;
+    }
+    Ok(paths
+        .into_iter()
+        .filter(|s| !s.ends_with("/mod.rs"))
+        .collect())
+}
+
+// Regenerates src/entities from the migrations via a scratch sqlite
+// database, when a migration is newer than the entities. Off without the
+// `entities` feature or with FRQ_SKIP_ENTITIES=1, e.g. for offline builds
+// of a tree whose entities were generated before.
+async fn generate_entities(migrations: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if cfg!(not(feature = "entities")) || skip("FRQ_SKIP_ENTITIES") {
+        if !Path::new(relative!("src/entities/mod.rs")).exists() {
+            println!("cargo::warning=entity generation skipped and src/entities is missing");
+        }
+        return Ok(());
+    }
+    if !stale(relative!("src/entities/mod.rs"), migrations) {
+        return Ok(());
+    }
+
+    // Start from an empty scratch database every time
+    let _ = std::fs::remove_file(relative!(".entity-gen-migr.tmpdb"));
+    let mut generator = Command::new("cargo");
+    generator.args(["run", "--release"]);
+    if std::env::var("CARGO_NET_OFFLINE").is_ok_and(|v| v == "true") {
+        generator.arg("--offline");
+    }
+    generator
         .current_dir(relative!("utils/migrator-entity-generator"))
         .status()?
         .eok()?;
@@ -109,7 +236,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )
     .await?;
     std::fs::remove_file(relative!(".entity-gen-migr.tmpdb"))?;
-
     Ok(())
 }
 
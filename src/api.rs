@@ -0,0 +1,234 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+mod accounts;
+mod password_reset;
+mod session;
+mod status;
+mod totp;
+mod webauthn;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Catcher, Request, Response, Route,
+};
+use sea_orm::DbErr;
+use serde_derive::Serialize;
+
+use crate::request_id::RequestId;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        accounts::create_account,
+        accounts::verify_email,
+        accounts::password_policy,
+        password_reset::request,
+        password_reset::confirm,
+        session::login,
+        session::current,
+        session::logout,
+        session::second_factor,
+        status::health,
+        status::ready,
+        status::version,
+        totp::enroll,
+        totp::confirm,
+        totp::disable,
+        webauthn::register_start,
+        webauthn::register_finish,
+        webauthn::list,
+        webauthn::remove,
+        webauthn::login_start,
+        webauthn::login_finish
+    ]
+}
+
+// Registered under /api, so every API error is JSON; see ApiError
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        payload_too_large,
+        unprocessable_entity,
+        too_many_requests,
+        internal_error,
+        default_catcher
+    ]
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+// Error returned by every /api/ route, rendered as
+// { "error": { "code": ..., "message": ..., "request_id": ..., "field": ..., "rules": [...] } }
+// `code` is stable and meant for machines; `message` is for humans.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub code: &'static str,
+    pub message: String,
+    // Form field the error refers to, if any
+    pub field: Option<&'static str>,
+    // Codes of the individual rules the field broke, e.g. password_policy::Rule
+    pub rules: Vec<&'static str>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            field: None,
+            rules: vec![],
+        }
+    }
+
+    pub fn on_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    pub fn with_rules(mut self, rules: Vec<&'static str>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, code, message)
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Status::Conflict, code, message)
+    }
+
+    pub fn internal() -> Self {
+        Self::new(
+            Status::InternalServerError,
+            "internal_error",
+            "an internal error occurred",
+        )
+    }
+}
+
+// Database errors are logged but never shown to clients
+impl From<DbErr> for ApiError {
+    fn from(e: DbErr) -> Self {
+        error!("database error: {}", e);
+        ApiError::internal()
+    }
+}
+
+// For the command line, where there is no response to render into
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.field {
+            Some(field) => write!(f, "{}: {}", field, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    request_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    rules: &'a [&'static str],
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = Json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code,
+                message: &self.message,
+                request_id: &RequestId::of(req).0,
+                field: self.field,
+                rules: &self.rules,
+            },
+        })
+        .respond_to(req)?;
+        Response::build_from(body).status(self.status).ok()
+    }
+}
+
+#[catch(400)]
+fn bad_request() -> ApiError {
+    ApiError::bad_request("bad_request", "the request is malformed")
+}
+
+#[catch(401)]
+fn unauthorized() -> ApiError {
+    ApiError::new(Status::Unauthorized, "unauthorized", "not logged in")
+}
+
+#[catch(403)]
+fn forbidden() -> ApiError {
+    ApiError::new(
+        Status::Forbidden,
+        "forbidden",
+        "you are not allowed to do this",
+    )
+}
+
+#[catch(404)]
+fn not_found(req: &Request<'_>) -> ApiError {
+    ApiError::new(
+        Status::NotFound,
+        "not_found",
+        format!("no such endpoint: {} {}", req.method(), req.uri().path()),
+    )
+}
+
+#[catch(413)]
+fn payload_too_large() -> ApiError {
+    ApiError::new(
+        Status::PayloadTooLarge,
+        "payload_too_large",
+        "the request body is too large",
+    )
+}
+
+// Rocket's Json guard fails with 422 on well-formed JSON of the wrong shape
+#[catch(422)]
+fn unprocessable_entity() -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity,
+        "unprocessable_entity",
+        "the request body is missing fields or has fields of the wrong type",
+    )
+}
+
+#[catch(429)]
+fn too_many_requests() -> ApiError {
+    ApiError::new(
+        Status::TooManyRequests,
+        "too_many_requests",
+        "too many requests; try again later",
+    )
+}
+
+#[catch(500)]
+fn internal_error() -> ApiError {
+    ApiError::internal()
+}
+
+#[catch(default)]
+fn default_catcher(status: Status) -> ApiError {
+    ApiError::new(status, "error", status.reason().unwrap_or("unknown error"))
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::api::ApiResult;
+use crate::config::AccountsConfig;
+use crate::dbms::Db;
+use crate::mail::Mailer;
+use crate::password_policy::{PasswordPolicy, PolicyInfo};
+use crate::tokens::TokenSigner;
+use crate::users::{self, NewAccount};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{response::status::Created, serde::json::Json, State};
+use sea_orm_rocket::Connection;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct AccountCreated {
+    pub id: Uuid,
+    pub username: String,
+}
+
+#[post("/accounts", format = "json", data = "<account>")]
+pub async fn create_account(
+    conn: Connection<'_, Db>,
+    accounts: &State<AccountsConfig>,
+    mailer: &State<Mailer>,
+    signer: &State<TokenSigner>,
+    policy: &State<PasswordPolicy>,
+    account: Json<NewAccount>,
+) -> ApiResult<Created<Json<AccountCreated>>> {
+    let acc = users::validate(policy, account.into_inner()).await?;
+    let user = users::create(conn.into_inner(), acc, false).await?;
+    // The account exists either way; a failed mail only delays verification
+    if let Err(e) =
+        users::send_verification(mailer, signer, &user, accounts.verification_token_hours).await
+    {
+        error!("failed to send verification mail to {}: {}", user.id, e);
+    }
+    Ok(
+        Created::new(format!("/api/accounts/{}", user.id)).body(Json(AccountCreated {
+            id: user.id,
+            username: user.username,
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct Verification {
+    pub token: String,
+}
+
+#[derive(Serialize)]
+pub struct Verified {
+    pub id: Uuid,
+    pub email: String,
+}
+
+#[post("/accounts/verify", format = "json", data = "<verification>")]
+pub async fn verify_email(
+    conn: Connection<'_, Db>,
+    signer: &State<TokenSigner>,
+    verification: Json<Verification>,
+) -> ApiResult<Json<Verified>> {
+    let user = users::verify_email(conn.into_inner(), signer, &verification.token).await?;
+    Ok(Json(Verified {
+        id: user.id,
+        email: user.email,
+    }))
+}
+
+// The password rules, so the form can check them as the user types
+#[get("/password-policy")]
+pub fn password_policy(policy: &State<PasswordPolicy>) -> Json<PolicyInfo> {
+    Json(policy.info())
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::api::{ApiError, ApiResult};
+use crate::config::AccountsConfig;
+use crate::dbms::Db;
+use crate::mail::Mailer;
+use crate::password_policy::PasswordPolicy;
+use crate::proxy::ClientIp;
+use crate::ratelimit::PasswordResetLimits;
+use crate::users;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{http::Status, serde::json::Json, State};
+use sea_orm::DatabaseConnection;
+use sea_orm_rocket::Connection;
+use serde_derive::{Deserialize, Serialize};
+
+fn rate_limited() -> ApiError {
+    ApiError::new(
+        Status::TooManyRequests,
+        "too_many_requests",
+        "too many password reset attempts; try again later",
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct ResetRequested {
+    pub message: &'static str,
+}
+
+// Looks the address up and mails a reset link if it belongs to an enabled
+// account. Errors can only be logged; the client was answered already.
+async fn send_reset_link(
+    db: DatabaseConnection,
+    mailer: Mailer,
+    email: String,
+    minutes: i64,
+    ip: ClientIp,
+) {
+    let user = match users::find_by_login(&db, &email).await {
+        Ok(Some(user)) if !user.disabled => user,
+        Ok(_) => {
+            debug!("password reset requested for unknown or disabled {}", email);
+            return;
+        }
+        Err(e) => {
+            error!("failed to look up {} for a password reset: {}", email, e);
+            return;
+        }
+    };
+    info!("password reset requested for {} from {}", user.id, ip);
+    let token = match users::create_reset_token(&db, &user, minutes).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!(
+                "failed to create password reset token for {}: {}",
+                user.id, e
+            );
+            return;
+        }
+    };
+    if let Err(e) = users::send_password_reset(&mailer, &user, &token, minutes).await {
+        error!("failed to send password reset mail to {}: {}", user.id, e);
+    }
+}
+
+// Always answers the same way, whether or not the address belongs to an
+// account. The lookup, token and mail all happen in a background task, so
+// every request does the same work before the response and timing doesn't
+// tell either.
+#[post("/password-reset/request", format = "json", data = "<req>")]
+pub async fn request(
+    conn: Connection<'_, Db>,
+    accounts: &State<AccountsConfig>,
+    mailer: &State<Mailer>,
+    limits: &State<PasswordResetLimits>,
+    ip: ClientIp,
+    req: Json<ResetRequest>,
+) -> ApiResult<(Status, Json<ResetRequested>)> {
+    let email = req.email.trim().to_lowercase();
+    if !limits.per_ip.check(&ip.to_string()) || !limits.per_email.check(&email) {
+        return Err(rate_limited());
+    }
+    // Only addresses, so usernames can't be probed either
+    if email.contains('@') {
+        rocket::tokio::spawn(send_reset_link(
+            conn.into_inner().clone(),
+            mailer.inner().clone(),
+            email,
+            accounts.password_reset_token_minutes,
+            ip,
+        ));
+    }
+    Ok((
+        Status::Accepted,
+        Json(ResetRequested {
+            message: "if an account uses this address, a reset link is on its way",
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ResetConfirmation {
+    pub token: String,
+    pub password: String,
+}
+
+#[post("/password-reset/confirm", format = "json", data = "<confirmation>")]
+pub async fn confirm(
+    conn: Connection<'_, Db>,
+    limits: &State<PasswordResetLimits>,
+    policy: &State<PasswordPolicy>,
+    ip: ClientIp,
+    confirmation: Json<ResetConfirmation>,
+) -> ApiResult<Status> {
+    if !limits.per_ip.check(&ip.to_string()) {
+        return Err(rate_limited());
+    }
+    let confirmation = confirmation.into_inner();
+    users::reset_password(
+        conn.into_inner(),
+        policy,
+        &confirmation.token,
+        confirmation.password,
+    )
+    .await?;
+    info!("password reset completed from {}", ip);
+    Ok(Status::NoContent)
+}
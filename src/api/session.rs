@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::api::totp::Code;
+use crate::api::{ApiError, ApiResult};
+use crate::auth::{self, AuthenticatedUser, PendingLogin};
+use crate::config::AccountsConfig;
+use crate::consts::*;
+use crate::dbms::Db;
+use crate::entities::{cookie, prelude::*, user};
+use crate::proxy::ClientIp;
+use crate::ratelimit::SecondFactorLimits;
+use crate::totp::{self, TotpCipher};
+use crate::users;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    http::{CookieJar, Status},
+    serde::json::Json,
+    State,
+};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter, Set,
+};
+use sea_orm_rocket::Connection;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    // Either the username or the email address
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub email_verified: bool,
+    // False until the second factor has been entered at /session/totp
+    pub authenticated: bool,
+    pub expires: chrono::NaiveDateTime,
+}
+
+impl SessionInfo {
+    pub(super) fn new(
+        user: user::Model,
+        authenticated: bool,
+        expires: chrono::NaiveDateTime,
+    ) -> Self {
+        SessionInfo {
+            id: user.id,
+            username: user.username,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            email_verified: user.email_verified,
+            authenticated,
+            expires,
+        }
+    }
+}
+
+fn bad_credentials() -> ApiError {
+    ApiError::new(
+        Status::Unauthorized,
+        "invalid_credentials",
+        "incorrect username or password",
+    )
+}
+
+#[post("/session", format = "json", data = "<creds>")]
+pub async fn login(
+    conn: Connection<'_, Db>,
+    jar: &CookieJar<'_>,
+    accounts: &State<AccountsConfig>,
+    ip: ClientIp,
+    creds: Json<Credentials>,
+) -> ApiResult<Json<SessionInfo>> {
+    let db = conn.into_inner();
+    let creds = creds.into_inner();
+    let found = users::find_by_login(db, &creds.username).await?;
+
+    let password = creds.password;
+    let user = rocket::tokio::task::spawn_blocking(move || match found {
+        Some(u) if auth::verify_password(&password, &u.hashed_password) => Some(u),
+        Some(_) => None,
+        None => {
+            auth::verify_dummy_password(&password);
+            None
+        }
+    })
+    .await
+    .map_err(|e| {
+        error!("password verification task failed: {}", e);
+        ApiError::internal()
+    })?
+    .ok_or_else(|| {
+        info!("failed login for {} from {}", creds.username, ip);
+        bad_credentials()
+    })?;
+    // Only reported once the password checked out, so it leaks nothing
+    check_allowed(accounts, &user)?;
+
+    // With a second factor, the session is only good for entering it
+    if totp::is_enabled(db, user.id).await? {
+        let expires = start_session(db, jar, user.id, false).await?;
+        info!(
+            "user {} needs a second factor to log in from {}",
+            user.id, ip
+        );
+        return Ok(Json(SessionInfo::new(user, false, expires)));
+    }
+    let expires = start_session(db, jar, user.id, true).await?;
+    info!("user {} logged in from {}", user.id, ip);
+
+    Ok(Json(SessionInfo::new(user, true, expires)))
+}
+
+// Whether the account may log in at all, once its owner has proven who
+// they are
+pub(super) fn check_allowed(accounts: &AccountsConfig, user: &user::Model) -> ApiResult<()> {
+    if user.disabled {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "account_disabled",
+            "this account has been disabled",
+        ));
+    }
+    if accounts.require_verified_email && !user.email_verified {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "email_unverified",
+            "confirm your email address using the link we sent you first",
+        ));
+    }
+    Ok(())
+}
+
+// Stores a new session and sets its cookie, returning its expiry
+pub(super) async fn start_session<C: ConnectionTrait>(
+    db: &C,
+    jar: &CookieJar<'_>,
+    user_id: Uuid,
+    authenticated: bool,
+) -> Result<chrono::NaiveDateTime, DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+    // Opportunistically clear out this user's stale sessions
+    Cookie::delete_many()
+        .filter(cookie::Column::UserId.eq(user_id))
+        .filter(cookie::Column::ExpiryDatetime.lte(now))
+        .exec(db)
+        .await?;
+
+    let id = Uuid::new_v4();
+    let expires = if authenticated {
+        now + chrono::Duration::hours(SESSION_LIFETIME_HOURS)
+    } else {
+        now + chrono::Duration::minutes(PENDING_LOGIN_MINUTES)
+    };
+    cookie::ActiveModel {
+        id: Set(id),
+        user_id: Set(user_id),
+        expiry_datetime: Set(expires),
+        authenticated: Set(authenticated),
+    }
+    .insert(db)
+    .await?;
+    jar.add_private(auth::session_cookie(id));
+    Ok(expires)
+}
+
+// Second step of logging in with TOTP enabled. Takes a code from the app
+// or a recovery code, and swaps the pending session for a full one.
+#[post("/session/totp", format = "json", data = "<code>")]
+pub async fn second_factor(
+    conn: Connection<'_, Db>,
+    jar: &CookieJar<'_>,
+    cipher: &State<TotpCipher>,
+    limits: &State<SecondFactorLimits>,
+    pending: PendingLogin,
+    ip: ClientIp,
+    code: Json<Code>,
+) -> ApiResult<Json<SessionInfo>> {
+    let db = conn.into_inner();
+    let user = pending.user;
+    // Per address too, so wrong codes from elsewhere don't lock the owner out
+    if !limits.login.check(&format!("{} {}", user.id, ip)) {
+        // Back to the password, which has its own cost
+        Cookie::delete_by_id(pending.session).exec(db).await?;
+        jar.remove_private(SESSION_COOKIE);
+        warn!(
+            "too many second factor attempts for {} from {}",
+            user.id, ip
+        );
+        return Err(ApiError::new(
+            Status::TooManyRequests,
+            "too_many_requests",
+            "too many incorrect codes; log in again later",
+        ));
+    }
+    if let Err(e) = totp::check(db, cipher, user.id, &code.code).await {
+        info!("failed second factor for {} from {}", user.id, ip);
+        return Err(e);
+    }
+    // A new id, so whoever saw the pending cookie doesn't get the session
+    Cookie::delete_by_id(pending.session).exec(db).await?;
+    let expires = start_session(db, jar, user.id, true).await?;
+    info!(
+        "user {} logged in from {} with a second factor",
+        user.id, ip
+    );
+    Ok(Json(SessionInfo::new(user, true, expires)))
+}
+
+#[get("/session")]
+pub async fn current(
+    conn: Connection<'_, Db>,
+    auth: AuthenticatedUser,
+) -> ApiResult<Json<SessionInfo>> {
+    let session = Cookie::find_by_id(auth.session)
+        .one(conn.into_inner())
+        .await?
+        .ok_or_else(|| ApiError::new(Status::Unauthorized, "no_session", "not logged in"))?;
+    Ok(Json(SessionInfo::new(
+        auth.user,
+        session.authenticated,
+        session.expiry_datetime,
+    )))
+}
+
+// Always succeeds, so a stale cookie can still be cleared client-side
+#[delete("/session")]
+pub async fn logout(conn: Connection<'_, Db>, jar: &CookieJar<'_>) -> ApiResult<Status> {
+    if let Some(id) = auth::session_id(jar) {
+        Cookie::delete_by_id(id).exec(conn.into_inner()).await?;
+        debug!("ended session {}", id);
+    }
+    jar.remove_private(SESSION_COOKIE);
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Probes for load balancers and orchestrators; no authentication required
+
+use crate::config::Dbms;
+use crate::consts::*;
+use crate::dbms::Db;
+use crate::migrator::Migrator;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{http::Status, serde::json::Json};
+use sea_orm_migration::MigratorTrait;
+use sea_orm_rocket::Connection;
+use serde_derive::Serialize;
+
+#[derive(Serialize)]
+pub struct Health {
+    pub status: &'static str,
+}
+
+// Liveness: the process is up and serving requests
+#[get("/health")]
+pub fn health() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub database: &'static str,
+    pub pending_migrations: Vec<String>,
+}
+
+// Readiness: the database answers and its schema is up to date.
+// 503 otherwise, so traffic is held back until migrations have run.
+#[get("/ready")]
+pub async fn ready(conn: Connection<'_, Db>) -> (Status, Json<Readiness>) {
+    let db = conn.into_inner();
+    let reachable = match db.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("readiness check: database ping failed: {}", e);
+            false
+        }
+    };
+    let pending = if reachable {
+        match Migrator::get_pending_migrations(db).await {
+            Ok(p) => Some(p.iter().map(|m| m.name().to_owned()).collect()),
+            Err(e) => {
+                warn!("readiness check: cannot list migrations: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let is_ready = pending.as_ref().is_some_and(Vec::is_empty);
+    (
+        if is_ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        Json(Readiness {
+            status: if is_ready { "ready" } else { "unavailable" },
+            database: if reachable { "ok" } else { "unreachable" },
+            pending_migrations: pending.unwrap_or_default(),
+        }),
+    )
+}
+
+#[derive(Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub edition: &'static str,
+    pub git_hash: &'static str,
+    pub database_backends: Vec<&'static str>,
+}
+
+#[get("/version")]
+pub fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        edition: EDITION,
+        git_hash: env!("FRQ_GIT_HASH"),
+        database_backends: Dbms::ALL.iter().map(Dbms::scheme).collect(),
+    })
+}
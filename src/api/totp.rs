@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::api::{ApiError, ApiResult};
+use crate::auth::AuthenticatedUser;
+use crate::dbms::Db;
+use crate::ratelimit::SecondFactorLimits;
+use crate::totp::{self, Enrollment, TotpCipher};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{http::Status, serde::json::Json, State};
+use sea_orm_rocket::Connection;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Code {
+    // Six digits from the app, or a recovery code where accepted
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    // Shown once; only hashes are kept
+    pub recovery_codes: Vec<String>,
+}
+
+fn rate_limited() -> ApiError {
+    ApiError::new(
+        Status::TooManyRequests,
+        "too_many_requests",
+        "too many incorrect codes; try again later",
+    )
+}
+
+#[post("/totp/enroll")]
+pub async fn enroll(
+    conn: Connection<'_, Db>,
+    cipher: &State<TotpCipher>,
+    auth: AuthenticatedUser,
+) -> ApiResult<Json<Enrollment>> {
+    Ok(Json(
+        totp::enroll(conn.into_inner(), cipher, &auth.user).await?,
+    ))
+}
+
+#[post("/totp/confirm", format = "json", data = "<code>")]
+pub async fn confirm(
+    conn: Connection<'_, Db>,
+    cipher: &State<TotpCipher>,
+    limits: &State<SecondFactorLimits>,
+    auth: AuthenticatedUser,
+    code: Json<Code>,
+) -> ApiResult<Json<RecoveryCodes>> {
+    if !limits.account.check(&auth.user.id.to_string()) {
+        return Err(rate_limited());
+    }
+    let codes = totp::confirm(conn.into_inner(), cipher, auth.user.id, &code.code).await?;
+    Ok(Json(RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
+
+// Needs a current code (or a recovery code), so a hijacked session alone
+// can't turn the second factor off
+#[post("/totp/disable", format = "json", data = "<code>")]
+pub async fn disable(
+    conn: Connection<'_, Db>,
+    cipher: &State<TotpCipher>,
+    limits: &State<SecondFactorLimits>,
+    auth: AuthenticatedUser,
+    code: Json<Code>,
+) -> ApiResult<Status> {
+    if !limits.account.check(&auth.user.id.to_string()) {
+        return Err(rate_limited());
+    }
+    let db = conn.into_inner();
+    totp::check(db, cipher, auth.user.id, &code.code).await?;
+    totp::disable(db, auth.user.id).await?;
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::api::session::{self, SessionInfo};
+use crate::api::{ApiError, ApiResult};
+use crate::auth::AuthenticatedUser;
+use crate::config::AccountsConfig;
+use crate::dbms::Db;
+use crate::entities::webauthn_credential;
+use crate::proxy::ClientIp;
+use crate::ratelimit::PasskeyLoginLimits;
+use crate::webauthn::{self, AssertionCredential, Passkeys, RegistrationCredential};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    http::{CookieJar, Status},
+    response::status::Created,
+    serde::json::Json,
+    State,
+};
+use sea_orm_rocket::Connection;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct NewPasskey {
+    // Label to tell the user's passkeys apart, e.g. "Laptop"
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub transports: Vec<String>,
+    pub created: chrono::NaiveDateTime,
+    pub last_used: Option<chrono::NaiveDateTime>,
+}
+
+impl From<webauthn_credential::Model> for PasskeyInfo {
+    fn from(cred: webauthn_credential::Model) -> Self {
+        PasskeyInfo {
+            id: cred.id,
+            name: cred.name,
+            transports: cred
+                .transports
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            created: cred.created_datetime,
+            last_used: cred.last_used_datetime,
+        }
+    }
+}
+
+#[post("/webauthn/register/start")]
+pub async fn register_start(
+    conn: Connection<'_, Db>,
+    passkeys: &State<Passkeys>,
+    auth: AuthenticatedUser,
+) -> ApiResult<Json<serde_json::Value>> {
+    Ok(Json(
+        passkeys
+            .start_registration(conn.into_inner(), &auth.user)
+            .await?,
+    ))
+}
+
+#[post("/webauthn/register/finish", format = "json", data = "<passkey>")]
+pub async fn register_finish(
+    conn: Connection<'_, Db>,
+    passkeys: &State<Passkeys>,
+    auth: AuthenticatedUser,
+    passkey: Json<NewPasskey>,
+) -> ApiResult<Created<Json<PasskeyInfo>>> {
+    let cred = passkeys
+        .finish_registration(
+            conn.into_inner(),
+            auth.user.id,
+            &passkey.name,
+            &passkey.credential,
+        )
+        .await?;
+    Ok(Created::new(format!("/api/webauthn/credentials/{}", cred.id)).body(Json(cred.into())))
+}
+
+#[get("/webauthn/credentials")]
+pub async fn list(
+    conn: Connection<'_, Db>,
+    auth: AuthenticatedUser,
+) -> ApiResult<Json<Vec<PasskeyInfo>>> {
+    let creds = webauthn::credentials(conn.into_inner(), auth.user.id).await?;
+    Ok(Json(creds.into_iter().map(Into::into).collect()))
+}
+
+#[delete("/webauthn/credentials/<id>")]
+pub async fn remove(
+    conn: Connection<'_, Db>,
+    auth: AuthenticatedUser,
+    id: &str,
+) -> ApiResult<Status> {
+    let not_found = || ApiError::new(Status::NotFound, "not_found", "no such passkey");
+    let id = Uuid::parse_str(id).map_err(|_| not_found())?;
+    if webauthn::remove(conn.into_inner(), auth.user.id, id).await? {
+        Ok(Status::NoContent)
+    } else {
+        Err(not_found())
+    }
+}
+
+// Anyone may call this, so it is limited per client address to keep one
+// client from filling up the pending challenges
+#[post("/webauthn/login/start")]
+pub fn login_start(
+    passkeys: &State<Passkeys>,
+    limits: &State<PasskeyLoginLimits>,
+    ip: ClientIp,
+) -> ApiResult<Json<serde_json::Value>> {
+    if !limits.per_ip.check(&ip.to_string()) {
+        return Err(ApiError::new(
+            Status::TooManyRequests,
+            "too_many_requests",
+            "too many passkey logins; try again later",
+        ));
+    }
+    Ok(Json(passkeys.start_login()?))
+}
+
+// Logs in without a password. User verification on the authenticator
+// stands in for the second factor, so TOTP isn't asked for.
+#[post("/webauthn/login/finish", format = "json", data = "<credential>")]
+pub async fn login_finish(
+    conn: Connection<'_, Db>,
+    jar: &CookieJar<'_>,
+    accounts: &State<AccountsConfig>,
+    passkeys: &State<Passkeys>,
+    ip: ClientIp,
+    credential: Json<AssertionCredential>,
+) -> ApiResult<Json<SessionInfo>> {
+    let db = conn.into_inner();
+    let user = passkeys.finish_login(db, &credential).await.map_err(|e| {
+        info!("failed passkey login from {}", ip);
+        e
+    })?;
+    session::check_allowed(accounts, &user)?;
+    let expires = session::start_session(db, jar, user.id, true).await?;
+    info!("user {} logged in from {} with a passkey", user.id, ip);
+    Ok(Json(SessionInfo::new(user, true, expires)))
+}
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::consts::*;
+use crate::dbms::Db;
+use crate::entities::{cookie, user};
+
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    request::{FromRequest, Outcome},
+    Request,
+};
+use sea_orm::{EntityTrait, ModelTrait};
+use sea_orm_rocket::Database;
+use uuid::Uuid;
+
+pub struct HashedPassword {
+    pub salt: String,
+    // Full PHC string ($argon2id$v=19$...), which also embeds the salt
+    pub hash: String,
+}
+
+// Argon2::default() is Argon2id v19 with the OWASP-recommended parameters
+pub fn hash_password(password: &str) -> Result<HashedPassword, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string();
+    Ok(HashedPassword {
+        salt: salt.as_str().to_owned(),
+        hash,
+    })
+}
+
+// Parameters are taken from the stored PHC string, not Argon2::default(),
+// so hashes made with older parameters keep verifying
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            error!("stored password hash is malformed: {}", e);
+            false
+        }
+    }
+}
+
+// Burns the same time as a real verification, so failed logins for
+// unknown users can't be told apart from wrong passwords by timing
+pub fn verify_dummy_password(password: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY.get_or_init(|| {
+        hash_password("fastrequest-dummy-password")
+            .map(|h| h.hash)
+            .unwrap_or_default()
+    });
+    let _ = verify_password(password, hash);
+}
+
+// Stand-in for a missing session secret key; sessions won't survive a restart
+pub fn ephemeral_secret_key() -> Vec<u8> {
+    let mut key = vec![0u8; 64];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Unguessable token for links sent by email, 256 bits in base64url
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn session_cookie(id: Uuid) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, id.to_string()))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(rocket::time::Duration::hours(SESSION_LIFETIME_HOURS))
+        .build()
+}
+
+// The session id stored in the (encrypted) session cookie, if any
+pub fn session_id(jar: &CookieJar<'_>) -> Option<Uuid> {
+    jar.get_private(SESSION_COOKIE)
+        .and_then(|c| Uuid::parse_str(c.value()).ok())
+}
+
+// A session cookie resolved to its `cookie` row and owning `user` row.
+// Cached per request, so several guards only hit the database once.
+struct Session {
+    user: user::Model,
+    session: cookie::Model,
+}
+
+// Request guard for routes that need a logged-in user.
+// Resolves the private session cookie to its `cookie` row and owning
+// `user` row; fails with 401 if either is missing, the session expired,
+// or the login still waits for its second factor (see PendingLogin).
+#[derive(Clone)]
+pub struct AuthenticatedUser {
+    pub user: user::Model,
+    pub session: Uuid,
+}
+
+async fn resolve_session(req: &Request<'_>) -> Result<Session, Status> {
+    let db = Db::fetch(req.rocket())
+        .ok_or_else(|| {
+            error!("database pool is not attached");
+            Status::InternalServerError
+        })?
+        .conn();
+    let id = session_id(req.cookies()).ok_or(Status::Unauthorized)?;
+    let (session, user) = match cookie::Entity::find_by_id(id)
+        .find_also_related(user::Entity)
+        .one(db)
+        .await
+    {
+        Ok(Some((session, Some(user)))) => (session, user),
+        Ok(_) => {
+            debug!("session {} does not exist", id);
+            req.cookies().remove_private(SESSION_COOKIE);
+            return Err(Status::Unauthorized);
+        }
+        Err(e) => {
+            error!("failed to look up session: {}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    if user.disabled {
+        debug!("session {} belongs to disabled user {}", id, user.id);
+        return Err(Status::Unauthorized);
+    }
+    if session.expiry_datetime <= chrono::Utc::now().naive_utc() {
+        debug!("session {} expired", id);
+        if let Err(e) = session.delete(db).await {
+            warn!("failed to delete expired session {}: {}", id, e);
+        }
+        req.cookies().remove_private(SESSION_COOKIE);
+        return Err(Status::Unauthorized);
+    }
+    Ok(Session { user, session })
+}
+
+async fn cached_session<'r>(req: &'r Request<'_>) -> &'r Result<Session, Status> {
+    req.local_cache_async(resolve_session(req)).await
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match cached_session(req).await {
+            Ok(s) if s.session.authenticated => Outcome::Success(AuthenticatedUser {
+                user: s.user.clone(),
+                session: s.session.id,
+            }),
+            Ok(s) => {
+                debug!("session {} is waiting for a second factor", s.session.id);
+                Outcome::Error((Status::Unauthorized, ()))
+            }
+            Err(status) => Outcome::Error((*status, ())),
+        }
+    }
+}
+
+// Request guard for completing a login: a session whose password checked
+// out but whose second factor hasn't yet. Fully authenticated sessions
+// fail with 409.
+pub struct PendingLogin {
+    pub user: user::Model,
+    pub session: Uuid,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PendingLogin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match cached_session(req).await {
+            Ok(s) if !s.session.authenticated => Outcome::Success(PendingLogin {
+                user: s.user.clone(),
+                session: s.session.id,
+            }),
+            Ok(_) => Outcome::Error((Status::Conflict, ())),
+            Err(status) => Outcome::Error((*status, ())),
+        }
+    }
+}
+
+// The fully authenticated user an earlier guard resolved for this
+// request, if any; never touches the database
+pub fn cached_user_id(req: &Request<'_>) -> Option<Uuid> {
+    req.local_cache(|| Err::<Session, Status>(Status::Unauthorized))
+        .as_ref()
+        .ok()
+        .filter(|s| s.session.authenticated)
+        .map(|s| s.user.id)
+}
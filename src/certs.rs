@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Reloads the TLS certificate without restarting, so rotations don't drop
+// in-flight HTTP/2 and HTTP/3 connections. New handshakes pick up the new
+// certificate once its files change (checked every ssl.reload_interval
+// seconds) or, on Unix, the process receives SIGHUP.
+
+use crate::config::SslConfig;
+
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    tls::{ClientHello, Resolver, ServerConfig, TlsConfig},
+    tokio, Build, Rocket,
+};
+
+// Figment key main.rs stores [ssl] under, for `init` to find
+pub const FIGMENT_KEY: &str = "fastrequest.ssl";
+
+pub struct ReloadingResolver {
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+// Logs when the certificate in `path` expires; purely informational
+fn log_expiry(path: &str) {
+    let expiry = std::fs::read(path).ok().and_then(|pem| {
+        let start = pem.windows(10).position(|w| w == b"-----BEGIN")?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem[start..]).ok()?;
+        let cert = pem.parse_x509().ok()?;
+        Some(cert.validity().not_after)
+    });
+    match expiry {
+        Some(t) => info!("TLS certificate {} expires {}", path, t),
+        None => warn!("could not read the expiry date of {}", path),
+    }
+}
+
+fn modified(ssl: &SslConfig) -> Option<(SystemTime, SystemTime)> {
+    let m = |p: &str| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    Some((m(&ssl.cert)?, m(&ssl.key)?))
+}
+
+async fn reload(tls: &TlsConfig, ssl: &SslConfig, current: &RwLock<Arc<ServerConfig>>) {
+    // A half-written or mismatched pair fails here; keep serving the old one
+    match tls.server_config().await {
+        Ok(config) => {
+            *current.write().unwrap() = Arc::new(config);
+            info!("reloaded TLS certificate");
+            log_expiry(&ssl.cert);
+        }
+        Err(e) => error!(
+            "failed to reload TLS certificate, keeping the old one: {}",
+            e
+        ),
+    }
+}
+
+// Seconds between checks when reload_interval is 0 but SIGHUP can't be
+// received, e.g. on non-Unix systems
+const FALLBACK_INTERVAL: u64 = 60;
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Ok(s) => Hangup(Some(s)),
+            Err(e) => {
+                warn!("cannot listen for SIGHUP: {}", e);
+                Hangup(None)
+            }
+        }
+    }
+
+    fn available(&self) -> bool {
+        self.0.is_some()
+    }
+
+    async fn recv(&mut self) -> Option<()> {
+        self.0.as_mut()?.recv().await
+    }
+}
+
+// No SIGHUP outside Unix; certificates are only polled for
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Hangup
+    }
+
+    fn available(&self) -> bool {
+        false
+    }
+
+    async fn recv(&mut self) -> Option<()> {
+        None
+    }
+}
+
+async fn watch(tls: TlsConfig, ssl: SslConfig, current: Arc<RwLock<Arc<ServerConfig>>>) {
+    let mut hangup = Hangup::new();
+    let seconds = if ssl.reload_interval == 0 && !hangup.available() {
+        warn!(
+            "SIGHUP is unavailable; checking for a new TLS certificate every {} seconds",
+            FALLBACK_INTERVAL
+        );
+        FALLBACK_INTERVAL
+    } else {
+        ssl.reload_interval
+    };
+    let mut last = modified(&ssl);
+    let interval = Duration::from_secs(seconds.max(1));
+    loop {
+        tokio::select! {
+            Some(_) = hangup.recv() => {
+                info!("SIGHUP received; reloading TLS certificate");
+            }
+            _ = tokio::time::sleep(interval), if seconds > 0 => {
+                let now = modified(&ssl);
+                if now.is_none() || now == last {
+                    continue;
+                }
+                debug!("TLS certificate files changed");
+            }
+        }
+        last = modified(&ssl);
+        reload(&tls, &ssl, &current).await;
+    }
+}
+
+#[rocket::async_trait]
+impl Resolver for ReloadingResolver {
+    async fn init(rocket: &Rocket<Build>) -> rocket::tls::Result<Self> {
+        // Reported by Rocket as a launch error
+        let invalid = |what: &str, e: rocket::figment::Error| {
+            rocket::tls::Error::Io(io::Error::other(format!("invalid {}: {}", what, e)))
+        };
+        let tls: TlsConfig = rocket
+            .figment()
+            .extract_inner("tls")
+            .map_err(|e| invalid("TLS configuration", e))?;
+        let ssl: SslConfig = rocket
+            .figment()
+            .extract_inner(FIGMENT_KEY)
+            .map_err(|e| invalid("[ssl] configuration", e))?;
+        let current = Arc::new(RwLock::new(Arc::new(tls.server_config().await?)));
+        log_expiry(&ssl.cert);
+        tokio::spawn(watch(tls, ssl, current.clone()));
+        Ok(ReloadingResolver { current })
+    }
+
+    async fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<ServerConfig>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
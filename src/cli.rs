@@ -0,0 +1,418 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::config::{Config, Secrets};
+use crate::dbms;
+use crate::devcert;
+use crate::logging;
+use crate::migrator::Migrator;
+use crate::password_policy::PasswordPolicy;
+use crate::proxy::TrustedProxies;
+use crate::scaffold;
+use crate::totp;
+use crate::users::{self, NewAccount};
+
+use std::error::Error;
+use std::io::BufRead;
+use std::path::Path;
+
+use clap::{Parser, Subcommand};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::fs::relative;
+use sea_orm::DatabaseConnection;
+use sea_orm_migration::{MigrationStatus, MigratorTrait};
+
+#[derive(Parser)]
+#[command(version, about = "FastRequest server and administration tool")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server (the default)
+    Serve,
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage database migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Administer user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Generate a self-signed certificate for local development
+    DevCert {
+        /// Where to write the certificate
+        #[arg(long, default_value = relative!("secrets/cert.pem"))]
+        cert: String,
+        /// Where to write the private key
+        #[arg(long, default_value = relative!("secrets/key.pem"))]
+        key: String,
+        /// Additional host names or addresses the certificate is valid for
+        #[arg(long = "host")]
+        hosts: Vec<String>,
+        /// Replace an existing certificate and key
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Load and validate the configuration and secrets, then print them
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply pending migrations
+    Up {
+        /// Number of migrations to apply (default: all)
+        steps: Option<u32>,
+    },
+    /// Roll back applied migrations
+    Down {
+        /// Number of migrations to roll back
+        #[arg(default_value_t = 1)]
+        steps: u32,
+    },
+    /// List migrations and whether they have been applied
+    Status,
+    /// Drop every table, then apply all migrations
+    Fresh {
+        /// Confirm that all data should be deleted
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Create a timestamped migration skeleton in src/migrator
+    New {
+        /// snake_case description, e.g. create_table_request
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a new account
+    Create {
+        #[arg(long)]
+        username: String,
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        first_name: String,
+        #[arg(long)]
+        last_name: String,
+        #[arg(long)]
+        organization: Option<String>,
+        #[arg(long)]
+        phone: Option<String>,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Prevent a user from logging in and end their sessions
+    Disable {
+        /// Username or email address
+        user: String,
+    },
+    /// Allow a disabled user to log in again
+    Enable {
+        /// Username or email address
+        user: String,
+    },
+    /// Mark a user's email address as verified
+    Verify {
+        /// Username or email address
+        user: String,
+    },
+    /// Remove a user's TOTP authenticator and recovery codes, e.g. after
+    /// they lost both
+    #[command(name = "disable-2fa")]
+    Disable2fa {
+        /// Username or email address
+        user: String,
+    },
+    /// Set a new password and end the user's sessions
+    ResetPassword {
+        /// Username or email address
+        user: String,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+}
+
+pub async fn run(cmd: Command) -> Result<(), Box<dyn Error>> {
+    match cmd {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Config(ConfigCommand::Check) => config_check(),
+        Command::Migrate(c) => migrate(c).await,
+        Command::User(c) => user(c).await,
+        Command::DevCert {
+            cert,
+            key,
+            hosts,
+            force,
+        } => {
+            devcert::warn_release();
+            devcert::generate(&cert, &key, &hosts, force)?;
+            println!("wrote {} and {}", cert, key);
+            Ok(())
+        }
+    }
+}
+
+fn load() -> Result<(Config, Secrets), Box<dyn Error>> {
+    let conf = Config::load_config().map_err(|e| e.diagnostic())?;
+    logging::configure(&conf.logging);
+    let secrets = Secrets::new(&conf).map_err(|e| e.diagnostic())?;
+    Ok((conf, secrets))
+}
+
+async fn connect() -> Result<(Config, DatabaseConnection), Box<dyn Error>> {
+    let (conf, secrets) = load()?;
+    let url = dbms::get_url(&conf, &secrets).map_err(|e| e.diagnostic())?;
+    let db = sea_orm::Database::connect(url).await?;
+    Ok((conf, db))
+}
+
+fn config_check() -> Result<(), Box<dyn Error>> {
+    let path = Config::locate().map_err(|e| e.diagnostic())?;
+    let conf = Config::from_file(&path).map_err(|e| e.diagnostic())?;
+    let secrets = Secrets::new(&conf).map_err(|e| e.diagnostic())?;
+    dbms::get_url(&conf, &secrets).map_err(|e| e.diagnostic())?;
+    TrustedProxies::new(&conf.settings.trusted_proxies)
+        .map_err(|e| format!("settings.trusted_proxies: {}", e))?;
+    let set = |b: bool| if b { "(set)" } else { "(not set)" };
+
+    println!("configuration: {}", path);
+    println!("[settings]");
+    println!(
+        "  secrets_file    = {}",
+        conf.settings.secrets_file.as_deref().unwrap_or("(none)")
+    );
+    println!("  use_env_secrets = {}", conf.settings.use_env_secrets);
+    println!("  address         = {}", conf.settings.address);
+    println!("  port            = {}", conf.settings.port);
+    println!(
+        "  url             = {}",
+        conf.settings.url.as_deref().unwrap_or("(none)")
+    );
+    println!(
+        "  trusted_proxies = {}",
+        conf.settings.trusted_proxies.join(", ")
+    );
+    match conf.ssl {
+        Some(ref ssl) => {
+            println!("[ssl]");
+            println!("  cert            = {}", ssl.cert);
+            println!("  key             = {}", ssl.key);
+            println!("  reload_interval = {}", ssl.reload_interval);
+        }
+        None => println!("[ssl] not configured; serving plain HTTP"),
+    }
+    println!("[db]");
+    println!("  dbms            = {}", conf.db.dbms.scheme());
+    println!("  username        = {}", conf.db.username);
+    println!("  database        = {}", conf.db.database);
+    println!("  address         = {}", conf.db.address);
+    println!("  port            = {}", conf.db.port);
+    println!("  tls             = {:?}", conf.db.tls);
+    println!(
+        "  ca_cert         = {}",
+        conf.db.ca_cert.as_deref().unwrap_or("(none)")
+    );
+    println!("  url             = {}", dbms::redacted_url(&conf));
+    println!("  auto_migrate    = {:?}", conf.db.auto_migrate);
+    println!("[accounts]");
+    println!(
+        "  require_verified_email   = {}",
+        conf.accounts.require_verified_email
+    );
+    println!(
+        "  verification_token_hours = {}",
+        conf.accounts.verification_token_hours
+    );
+    let policy = &conf.password_policy;
+    // Also reads breached_list, so a malformed one shows up here
+    PasswordPolicy::new(policy)?;
+    println!("[password_policy]");
+    println!("  min_length           = {}", policy.min_length);
+    println!("  require_mixed_case   = {}", policy.require_mixed_case);
+    println!("  require_digit        = {}", policy.require_digit);
+    println!("  forbid_personal_info = {}", policy.forbid_personal_info);
+    println!(
+        "  breached_list        = {}",
+        policy.breached_list.as_deref().unwrap_or("(none)")
+    );
+    println!("[mail]");
+    println!("  transport       = {:?}", conf.mail.transport);
+    println!("  from            = {}", conf.mail.from);
+    println!("[logging]");
+    println!("  format          = {:?}", conf.logging.format);
+    println!(
+        "  level           = {}",
+        conf.logging
+            .level
+            .map_or("(build default)".to_owned(), |l| l.to_string())
+    );
+    for (module, level) in &conf.logging.modules {
+        println!("  modules.{} = {}", module, level);
+    }
+    println!("  access_log      = {}", conf.logging.access_log);
+    println!("[secrets]");
+    println!("  db.password     = {}", set(secrets.db.is_some()));
+    println!("  session.key     = {}", set(secrets.session.is_some()));
+    println!("  mail.password   = {}", set(secrets.mail.is_some()));
+    println!("  totp.key        = {}", set(secrets.totp.is_some()));
+    println!("configuration OK");
+    Ok(())
+}
+
+async fn migrate(cmd: MigrateCommand) -> Result<(), Box<dyn Error>> {
+    // Works on the source tree, so needs neither config nor database
+    if let MigrateCommand::New { name } = cmd {
+        let path = scaffold::new_migration(
+            Path::new(relative!("src/migrator")),
+            &name,
+            chrono::Utc::now(),
+        )?;
+        println!("created {}", path);
+        println!("it will be included in the next build");
+        return Ok(());
+    }
+    let (_, db) = connect().await?;
+    match cmd {
+        MigrateCommand::Up { steps } => {
+            let pending = Migrator::get_pending_migrations(&db).await?;
+            if pending.is_empty() {
+                println!("no pending migrations");
+            } else {
+                Migrator::up(&db, steps).await?;
+                let n = steps.map_or(pending.len(), |s| pending.len().min(s as usize));
+                for m in pending.iter().take(n) {
+                    println!("applied {}", m.name());
+                }
+            }
+        }
+        MigrateCommand::Down { steps } => {
+            let applied = Migrator::get_applied_migrations(&db).await?;
+            Migrator::down(&db, Some(steps)).await?;
+            for m in applied.iter().rev().take(steps as usize) {
+                println!("rolled back {}", m.name());
+            }
+        }
+        MigrateCommand::Status => {
+            for m in Migrator::get_migration_with_status(&db).await? {
+                let status = match m.status() {
+                    MigrationStatus::Applied => "applied",
+                    MigrationStatus::Pending => "pending",
+                };
+                println!("{:<8} {}", status, m.name());
+            }
+        }
+        MigrateCommand::Fresh { yes } => {
+            if !yes {
+                return Err("migrate fresh deletes all data; pass --yes to confirm".into());
+            }
+            Migrator::fresh(&db).await?;
+            println!("recreated database schema");
+        }
+        MigrateCommand::New { .. } => unreachable!("handled above"),
+    }
+    Ok(())
+}
+
+fn read_password(from_stdin: bool) -> Result<String, Box<dyn Error>> {
+    if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_owned());
+    }
+    let password = rpassword::prompt_password("New password: ")?;
+    if password != rpassword::prompt_password("Repeat password: ")? {
+        return Err("passwords do not match".into());
+    }
+    Ok(password)
+}
+
+async fn find_user(
+    db: &DatabaseConnection,
+    name: &str,
+) -> Result<crate::entities::user::Model, Box<dyn Error>> {
+    users::find_by_login(db, name)
+        .await?
+        .ok_or_else(|| format!("no user {}", name).into())
+}
+
+async fn user(cmd: UserCommand) -> Result<(), Box<dyn Error>> {
+    let (conf, db) = connect().await?;
+    match cmd {
+        UserCommand::Create {
+            username,
+            email,
+            first_name,
+            last_name,
+            organization,
+            phone,
+            password_stdin,
+        } => {
+            let policy = PasswordPolicy::new(&conf.password_policy)?;
+            let acc = users::validate(
+                &policy,
+                NewAccount {
+                    first_name,
+                    last_name,
+                    username,
+                    email,
+                    organization,
+                    phone,
+                    password: read_password(password_stdin)?,
+                },
+            )
+            .await?;
+            // Administrators vouch for the address
+            let user = users::create(&db, acc, true).await?;
+            println!("created user {} ({})", user.username, user.id);
+        }
+        UserCommand::Disable { user } => {
+            let u = find_user(&db, &user).await?;
+            users::set_disabled(&db, u, true).await?;
+            println!("disabled {}", user);
+        }
+        UserCommand::Enable { user } => {
+            let u = find_user(&db, &user).await?;
+            users::set_disabled(&db, u, false).await?;
+            println!("enabled {}", user);
+        }
+        UserCommand::Verify { user } => {
+            let u = find_user(&db, &user).await?;
+            users::set_email_verified(&db, u, true).await?;
+            println!("verified the email address of {}", user);
+        }
+        UserCommand::Disable2fa { user } => {
+            let u = find_user(&db, &user).await?;
+            if totp::disable(&db, u.id).await? {
+                println!("disabled two-factor authentication for {}", user);
+            } else {
+                println!("{} has no two-factor authentication set up", user);
+            }
+        }
+        UserCommand::ResetPassword {
+            user,
+            password_stdin,
+        } => {
+            let policy = PasswordPolicy::new(&conf.password_policy)?;
+            let u = find_user(&db, &user).await?;
+            users::set_password(&db, &policy, u, read_password(password_stdin)?).await?;
+            println!(
+                "changed password of {}; their sessions have been ended",
+                user
+            );
+        }
+    }
+    Ok(())
+}
@@ -3,7 +3,9 @@
 use crate::consts::*;
 use crate::utils::*;
 
+use std::collections::BTreeMap;
 use std::env;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -12,113 +14,754 @@ use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    pub ssl: SslConfig,
+    // Without [ssl], plain HTTP is served, e.g. behind a TLS-terminating proxy
+    pub ssl: Option<SslConfig>,
     pub settings: GeneralConfig,
     pub db: DbConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub accounts: AccountsConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub password_policy: PasswordPolicyConfig,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SslConfig {
     pub cert: String,
     pub key: String,
+    // Seconds between checks for a rotated cert/key; 0 = only on SIGHUP
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    60
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GeneralConfig {
     pub secrets_file: Option<String>,
     pub use_env_secrets: bool,
+    #[serde(default = "default_address")]
+    pub address: IpAddr,
     pub port: u16,
-    // Used for CORS
+    // Used for CORS if [cors] allowed_origins is empty, for links in mail,
+    // and as the passkey relying party (its host must be a domain name)
     pub url: Option<String>,
+    // Proxies (addresses or CIDR ranges) whose Forwarded/X-Forwarded-For
+    // headers are believed when determining client addresses
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+fn default_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    // Exact origins ("https://app.example.org"), all subdomains of one
+    // ("https://*.example.org"), or "*"
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // Seconds browsers may cache a preflight response
+    pub max_age: u64,
+    // Path prefixes served without Cross-Origin-Opener/Embedder-Policy,
+    // e.g. pages embedding third-party agency documents
+    pub isolation_exempt_paths: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS"]
+                .map(str::to_owned)
+                .to_vec(),
+            allowed_headers: ["Content-Type", "X-Request-Id"].map(str::to_owned).to_vec(),
+            allow_credentials: false,
+            max_age: 600,
+            isolation_exempt_paths: vec![],
+        }
+    }
+}
+
+// Upper bounds for the lifetimes of emailed links
+const MAX_VERIFICATION_TOKEN_HOURS: i64 = 24 * 30;
+const MAX_RESET_TOKEN_MINUTES: i64 = 24 * 60;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AccountsConfig {
+    // Refuse logins until the emailed verification link has been used
+    pub require_verified_email: bool,
+    pub verification_token_hours: i64,
+    pub password_reset_token_minutes: i64,
+    // Reset requests per hour, per address and per client address
+    pub password_reset_per_email: u32,
+    pub password_reset_per_ip: u32,
+    // Second factor codes tried per hour, per user: counted separately for
+    // each client address when logging in, and for changing the settings
+    pub second_factor_per_user: u32,
+    // Passkey logins started per client address, within the five minutes
+    // a login may take
+    pub passkey_login_per_ip: u32,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            require_verified_email: false,
+            verification_token_hours: 48,
+            password_reset_token_minutes: 60,
+            password_reset_per_email: 3,
+            password_reset_per_ip: 20,
+            second_factor_per_user: 10,
+            passkey_login_per_ip: 30,
+        }
+    }
+}
+
+// Rules for new passwords; the defaults match CreateAccountForm.svelte
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    // At least one upper and one lower case letter
+    pub require_mixed_case: bool,
+    pub require_digit: bool,
+    // No first name, last name or username inside the password
+    pub forbid_personal_info: bool,
+    // Pwned Passwords SHA-1 list: either one file of HASH:COUNT lines,
+    // loaded into memory, or a directory of range files (ABCDE.txt holding
+    // the SUFFIX:COUNT lines of hashes starting with ABCDE), read on demand
+    pub breached_list: Option<String>,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 8,
+            require_mixed_case: true,
+            require_digit: true,
+            forbid_personal_info: true,
+            breached_list: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    // "Name <address>" or just an address
+    pub from: String,
+    pub smtp: SmtpConfig,
+    // For the file transport
+    pub directory: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::default(),
+            from: "FastRequest <noreply@localhost>".to_owned(),
+            smtp: SmtpConfig::default(),
+            directory: "./mail".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    // Writes .eml files into mail.directory
+    File,
+    // Prints messages; for development
+    #[default]
+    Stdout,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    // Defaults to 587 for starttls, 465 for tls, 25 for none
+    pub port: Option<u16>,
+    // The password is smtp_password under [mail] in secrets
+    pub username: Option<String>,
+    pub tls: SmtpTls,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Starttls,
+    // Implicit TLS ("SMTPS")
+    Tls,
+    None,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    // Defaults to info for debug builds, warn for release builds
+    pub level: Option<log::LevelFilter>,
+    // Per-module overrides, e.g. "sqlx" = "warn"
+    pub modules: BTreeMap<String, log::LevelFilter>,
+    // One info line per response under the "access" target,
+    // regardless of `level`
+    pub access_log: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            level: None,
+            modules: BTreeMap::new(),
+            access_log: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // Colored, human-readable lines
+    #[default]
+    Pretty,
+    // One JSON object per line
+    Json,
+}
+
+// Prometheus metrics at /metrics
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    // Serve /metrics here only, instead of on the main port
+    pub listen: Option<SocketAddr>,
+    // If set, scrapers must send "Authorization: Bearer <token>"
+    pub bearer_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecurityConfig {
+    #[serde(default)]
+    pub headers: HeadersConfig,
+}
+
+// [security.headers]; empty strings omit a header
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadersConfig {
+    // Directive -> sources, merged over the built-in policy; an empty list
+    // removes a directive. Hashes of index.html's inline scripts are always
+    // added to script-src.
+    pub content_security_policy: BTreeMap<String, Vec<String>>,
+    pub csp_report_only: bool,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub hsts: HstsConfig,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        HeadersConfig {
+            content_security_policy: BTreeMap::new(),
+            csp_report_only: false,
+            referrer_policy: "strict-origin-when-cross-origin".to_owned(),
+            permissions_policy:
+                "camera=(), microphone=(), geolocation=(), payment=(), usb=(), interest-cohort=()"
+                    .to_owned(),
+            hsts: HstsConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HstsConfig {
+    // Unset: enabled if served over TLS or settings.url is https://,
+    // unless settings.url points at localhost
+    pub enabled: Option<bool>,
+    pub max_age_days: i64,
+    pub include_subdomains: bool,
+    // Implies include_subdomains; needs max_age_days >= 365
+    pub preload: bool,
+}
+
+impl Default for HstsConfig {
+    fn default() -> Self {
+        HstsConfig {
+            enabled: None,
+            max_age_days: 730,
+            include_subdomains: true,
+            preload: true,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DbConfig {
-    pub dbms: String,
+    pub dbms: Dbms,
     pub username: String,
+    // For sqlite, the path to the database file
     pub database: String,
+    // Host name, IP address, or an absolute path to a Unix socket
+    // (for postgres, the directory containing the socket)
     pub address: String,
     pub port: u16,
+    #[serde(default)]
+    pub tls: DbTlsMode,
+    // CA certificate used to verify the server with tls = "verify-ca"/"verify-full"
+    pub ca_cert: Option<String>,
+    #[serde(default)]
+    pub auto_migrate: AutoMigrate,
+    #[serde(default)]
+    pub pool: PoolConfig,
+}
+
+// [db.pool]; all timeouts are in seconds
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PoolConfig {
+    // Defaults to four per Rocket worker
+    pub max_connections: Option<u32>,
+    pub min_connections: u32,
+    pub connect_timeout: u64,
+    // How long a request waits for a free connection
+    pub acquire_timeout: u64,
+    pub idle_timeout: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    // Level every SQL statement is logged at; "off" to disable
+    pub log_level: log::LevelFilter,
+    // Statements slower than this (in milliseconds) are logged at slow_statement_level
+    pub slow_statement_threshold: u64,
+    pub slow_statement_level: log::LevelFilter,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections: None,
+            min_connections: 0,
+            connect_timeout: 5,
+            acquire_timeout: 30,
+            idle_timeout: Some(600),
+            max_lifetime: Some(1800),
+            log_level: log::LevelFilter::Trace,
+            slow_statement_threshold: 1000,
+            slow_statement_level: log::LevelFilter::Warn,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Dbms {
+    // Also covers MariaDB
+    Mysql,
+    Postgres,
+    Sqlite,
+}
+
+impl Dbms {
+    // Every backend this build can connect to
+    pub const ALL: [Dbms; 3] = [Dbms::Mysql, Dbms::Postgres, Dbms::Sqlite];
+
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Dbms::Mysql => "mysql",
+            Dbms::Postgres => "postgres",
+            Dbms::Sqlite => "sqlite",
+        }
+    }
+}
+
+// Named after PostgreSQL's sslmode values; ignored for sqlite
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DbTlsMode {
+    Disable,
+    // Use TLS if the server supports it
+    #[default]
+    Prefer,
+    Require,
+    // Require TLS and verify the certificate chain against ca_cert
+    VerifyCa,
+    // As verify-ca, and also check the host name
+    VerifyFull,
+}
+
+// What to do about pending migrations when the server starts.
+// Written in TOML as `auto_migrate = true | false | "check"`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(try_from = "AutoMigrateRepr", into = "AutoMigrateRepr")]
+pub enum AutoMigrate {
+    // Apply them (true)
+    #[default]
+    Apply,
+    // Leave the schema alone (false)
+    Off,
+    // Refuse to start while any are pending ("check")
+    Check,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AutoMigrateRepr {
+    Bool(bool),
+    Mode(String),
+}
+
+impl TryFrom<AutoMigrateRepr> for AutoMigrate {
+    type Error = String;
+
+    fn try_from(r: AutoMigrateRepr) -> Result<Self, Self::Error> {
+        match r {
+            AutoMigrateRepr::Bool(true) => Ok(AutoMigrate::Apply),
+            AutoMigrateRepr::Bool(false) => Ok(AutoMigrate::Off),
+            AutoMigrateRepr::Mode(m) if m == "check" => Ok(AutoMigrate::Check),
+            AutoMigrateRepr::Mode(m) => Err(format!(
+                "invalid auto_migrate \"{}\", expected true, false or \"check\"",
+                m
+            )),
+        }
+    }
+}
+
+impl From<AutoMigrate> for AutoMigrateRepr {
+    fn from(a: AutoMigrate) -> Self {
+        match a {
+            AutoMigrate::Apply => AutoMigrateRepr::Bool(true),
+            AutoMigrate::Off => AutoMigrateRepr::Bool(false),
+            AutoMigrate::Check => AutoMigrateRepr::Mode("check".to_owned()),
+        }
+    }
 }
 
 // Loads and verifies configuration
 impl Config {
-    pub fn load_config() -> Self {
-        // Get configuration path
-        let local_target = [&uw_nofile(env::var("HOME")), ".config/fastrequest.toml"].join("/");
-        let rel_target = relative!("fastrequest.toml");
-        let env_target = env::var("FRQ_CONFIG_PATH");
-        trace!(
-            "checking {:?}, {}, {}, {}",
-            env_target,
-            ETC_CONFIG_TARGET,
-            local_target,
-            rel_target
-        );
-        let conf_p = if let Ok(ref p) = env_target {
-            if !pexi(p) {
-                erxit("FRQ_CONFIG_PATH file does not exist");
-            }
-            p
-        } else if pexi(&local_target) {
-            &local_target
-        } else if pexi(ETC_CONFIG_TARGET) {
-            ETC_CONFIG_TARGET
-        } else if pexi(rel_target) {
-            rel_target
-        } else {
-            erxit("no configuration file found or specified");
+    pub fn load_config() -> Result<Self, ConfigError> {
+        Self::from_file(&Self::locate()?)
+    }
+
+    // Finds the configuration file to use, in order of precedence
+    pub fn locate() -> Result<String, ConfigError> {
+        if let Ok(p) = env::var("FRQ_CONFIG_PATH") {
+            trace!("using FRQ_CONFIG_PATH {}", p);
+            return if pexi(&p) {
+                Ok(p)
+            } else {
+                Err(ConfigError::MissingFile {
+                    key: "FRQ_CONFIG_PATH",
+                    path: p,
+                })
+            };
+        }
+        let mut candidates = vec![];
+        // HOME may legitimately be unset, e.g. under some service managers
+        if let Ok(home) = env::var("HOME") {
+            candidates.push([&home, ".config/fastrequest.toml"].join("/"));
+        }
+        candidates.push(ETC_CONFIG_TARGET.to_owned());
+        candidates.push(relative!("fastrequest.toml").to_owned());
+        trace!("checking {:?}", candidates);
+        match candidates.iter().position(|p| pexi(p)) {
+            Some(i) => Ok(candidates.swap_remove(i)),
+            None => Err(ConfigError::NotFound {
+                searched: candidates,
+            }),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let src = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_owned(),
+            source: e,
+        })?;
+        Self::parse(&src, path)
+    }
+
+    // `origin` is only used to name the source in errors
+    pub fn parse(src: &str, origin: &str) -> Result<Self, ConfigError> {
+        let mut config: Config =
+            toml::from_str(src).map_err(|e| ConfigError::parse(origin, src, true, e))?;
+        config.resolve_paths(relative!(""))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Rejects combinations of settings that parse fine but are unsafe
+    fn validate(&self) -> Result<(), ConfigError> {
+        // Would let any site make requests with the user's session cookie
+        // and read the responses
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|o| o.trim() == "*")
+        {
+            return Err(ConfigError::Invalid {
+                key: "cors.allow_credentials",
+                reason: "cannot be combined with the \"*\" origin".to_owned(),
+            });
+        }
+        // Emailed links; out of range values would overflow the expiry time
+        let bounded = |key: &'static str, value: i64, max: i64, unit: &str| {
+            if (1..=max).contains(&value) {
+                Ok(())
+            } else {
+                Err(ConfigError::Invalid {
+                    key,
+                    reason: format!("must be between 1 and {} {}", max, unit),
+                })
+            }
         };
+        bounded(
+            "accounts.verification_token_hours",
+            self.accounts.verification_token_hours,
+            MAX_VERIFICATION_TOKEN_HOURS,
+            "hours",
+        )?;
+        bounded(
+            "accounts.password_reset_token_minutes",
+            self.accounts.password_reset_token_minutes,
+            MAX_RESET_TOKEN_MINUTES,
+            "minutes",
+        )?;
+        Ok(())
+    }
+
+    // Makes paths starting with '.' relative to `base`, and checks that
+    // every referenced file exists
+    fn resolve_paths(&mut self, base: &str) -> Result<(), ConfigError> {
+        let resolve = |key: &'static str, p: &mut String| {
+            if p.starts_with('.') {
+                *p = [base, p.as_str()].join("");
+            }
+            if pexi(p) {
+                Ok(())
+            } else {
+                Err(ConfigError::MissingFile {
+                    key,
+                    path: p.clone(),
+                })
+            }
+        };
+        if let Some(ref mut ssl) = self.ssl {
+            resolve("ssl.cert", &mut ssl.cert)?;
+            resolve("ssl.key", &mut ssl.key)?;
+        }
+        if let Some(ref mut p) = self.settings.secrets_file {
+            resolve("settings.secrets_file", p)?;
+        }
+        if let Some(ref mut p) = self.db.ca_cert {
+            resolve("db.ca_cert", p)?;
+        }
+        if let Some(ref mut p) = self.password_policy.breached_list {
+            resolve("password_policy.breached_list", p)?;
+        }
+        // Created on demand, so it needn't exist yet
+        if self.mail.directory.starts_with('.') {
+            self.mail.directory = [base, self.mail.directory.as_str()].join("");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // No configuration file in any of the default locations
+    NotFound {
+        searched: Vec<String>,
+    },
+    // A path given in the configuration (or FRQ_CONFIG_PATH) does not exist
+    MissingFile {
+        key: &'static str,
+        path: String,
+    },
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        // 1-based line and column
+        location: Option<(usize, usize)>,
+        // Offending line; never set for files that contain secrets
+        snippet: Option<String>,
+        message: String,
+    },
+    // A setting has a value that is out of range or unsafe
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+    // Name of a secret that is required but was not provided
+    MissingSecret(&'static str),
+    // Neither a secrets file nor use_env_secrets is configured
+    NoSecretsSource,
+    // A *_FILE secrets variable names an unreadable file
+    SecretFile {
+        var: String,
+        path: String,
+        source: std::io::Error,
+    },
+}
 
-        // Load as TOML
-        let mut config: Config = toml::from_str(
-            &std::fs::read_to_string(conf_p)
-                .unwrap_or_else(|_| erxit("unable to read configuration file")),
-        )
-        .unwrap_or_else(|_| erxit("configuration file does not match schema"));
-
-        // Check legitimacy and replace relative paths
-        let base = relative!("");
-        // Run through all non-Option paths
-        for ptr in [&mut config.ssl.cert, &mut config.ssl.key] {
-            if ptr.chars().next() == Some('.') {
-                *ptr = [base, ptr].join("");
-            }
-            if !pexi(ptr) {
-                erxits(format! {"declared file {} does not exist", ptr});
-            }
-        }
-        for optr in [&mut config.settings.secrets_file] {
-            *optr = Some(
-                [
-                    base,
-                    if let Some(v) = optr {
-                        if v.chars().next() == Some('.') {
-                            v
-                        } else {
-                            continue;
-                        }
-                    } else {
-                        continue;
-                    },
-                ]
-                .join(""),
-            );
-            if let Some(p) = optr {
-                if !pexi(p) {
-                    erxits(format!("declared file {} does not exist", p));
+impl ConfigError {
+    fn parse(path: &str, src: &str, show_source: bool, e: toml::de::Error) -> Self {
+        let mut location = None;
+        let mut snippet = None;
+        if let Some(span) = e.span() {
+            let before = &src[..span.start];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let line = before.matches('\n').count() + 1;
+            let column = before[line_start..].chars().count() + 1;
+            location = Some((line, column));
+            if show_source {
+                snippet = src[line_start..].lines().next().map(str::to_owned);
+            }
+        }
+        ConfigError::Parse {
+            path: path.to_owned(),
+            location,
+            snippet,
+            message: e.message().trim().to_owned(),
+        }
+    }
+
+    // Multi-line, human-readable explanation for the terminal
+    pub fn diagnostic(&self) -> String {
+        let mut out = self.to_string();
+        let mut push = |s: String| {
+            out.push('\n');
+            out.push_str(&s);
+        };
+        match self {
+            ConfigError::NotFound { searched } => {
+                push("  searched, in order:".to_owned());
+                for p in searched {
+                    push(format!("    {}", p));
                 }
+                push("  = help: set FRQ_CONFIG_PATH to use a file elsewhere".to_owned());
+            }
+            ConfigError::MissingFile { key, .. } => {
+                push(
+                    "  = help: paths starting with '.' are relative to the source directory"
+                        .to_owned(),
+                );
+                if key.starts_with("ssl.") {
+                    push(
+                        "  = help: for local development, `fastrequest dev-cert` creates one"
+                            .to_owned(),
+                    );
+                }
+            }
+            ConfigError::Read { source, .. } | ConfigError::SecretFile { source, .. } => {
+                push(format!("  = cause: {}", source));
+            }
+            ConfigError::Parse {
+                path,
+                location,
+                snippet,
+                message,
+            } => {
+                if let Some((line, column)) = location {
+                    push(format!("  --> {}:{}:{}", path, line, column));
+                    if let Some(snippet) = snippet {
+                        let gutter = " ".repeat(line.to_string().len());
+                        push(format!("{} |", gutter));
+                        push(format!("{} | {}", line, snippet));
+                        push(format!("{} | {}^", gutter, " ".repeat(column - 1)));
+                    }
+                }
+                push(format!("  = {}", message));
+            }
+            ConfigError::Invalid { key, .. } if key.starts_with("cors.") => {
+                push("  = help: list the origins allowed to send credentials instead".to_owned());
+            }
+            ConfigError::Invalid { .. } => {}
+            ConfigError::MissingSecret(name) => {
+                push(format!(
+                    "  = help: set {} in the secrets file, or enable use_env_secrets",
+                    name
+                ));
+            }
+            ConfigError::NoSecretsSource => {
+                push("  = help: set settings.secrets_file, or enable use_env_secrets".to_owned());
             }
         }
+        out
+    }
+}
 
-        config
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NotFound { .. } => write!(f, "no configuration file found"),
+            ConfigError::MissingFile { key, path } => {
+                write!(f, "{} refers to {}, which does not exist", key, path)
+            }
+            ConfigError::Read { path, .. } => write!(f, "unable to read {}", path),
+            ConfigError::Parse { path, .. } => write!(f, "{} does not match schema", path),
+            ConfigError::Invalid { key, reason } => write!(f, "{} {}", key, reason),
+            ConfigError::MissingSecret(name) => write!(f, "secret {} is not set", name),
+            ConfigError::NoSecretsSource => write!(f, "no source of secrets is configured"),
+            ConfigError::SecretFile { var, path, .. } => {
+                write!(f, "unable to read {} ({})", var, path)
+            }
+        }
     }
 }
 
-#[derive(Deserialize)]
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } | ConfigError::SecretFile { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Secrets come from the TOML file at `settings.secrets_file`, and, if
+// `settings.use_env_secrets` is set, from the environment. For each secret,
+// the first of these that is set wins:
+//   1. the variable itself, e.g. FRQ_DB_PASSWORD
+//   2. a file named by the variable with a _FILE suffix, e.g.
+//      FRQ_DB_PASSWORD_FILE=/run/secrets/db_password (Docker/Kubernetes secrets)
+//   3. the value in the secrets file, if one is configured
+// Recognized variables:
+//   FRQ_DB_PASSWORD            -> db.password
+//   FRQ_SESSION_SECRET_KEY     -> session.secret_key
+//   FRQ_SMTP_PASSWORD          -> mail.smtp_password
+//   FRQ_TOTP_ENCRYPTION_KEY    -> totp.encryption_key
+#[derive(Deserialize, Default)]
 pub struct Secrets {
     // option because sqlite doesn't need secrets
     pub db: Option<DbSecrets>,
+    // option so development setups work without one; see auth::ephemeral_secret_key
+    pub session: Option<SessionSecrets>,
+    // only needed for SMTP servers requiring authentication
+    pub mail: Option<MailSecrets>,
+    // without it, two-factor authentication is unavailable; see totp::TotpCipher
+    pub totp: Option<TotpSecrets>,
 }
 
 #[derive(Deserialize)]
@@ -126,14 +769,296 @@ pub struct DbSecrets {
     pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct MailSecrets {
+    pub smtp_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpSecrets {
+    // Encrypts TOTP secrets at rest; changing it breaks enrolled authenticators
+    pub encryption_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct SessionSecrets {
+    // Encrypts private (session) cookies; 256-bit base64 or hex string
+    pub secret_key: String,
+}
+
 impl Secrets {
-    pub fn new(conf: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(conf: &Config) -> Result<Self, ConfigError> {
+        let file_secrets = |p: &str| -> Result<Self, ConfigError> {
+            let src = std::fs::read_to_string(p).map_err(|e| ConfigError::Read {
+                path: p.to_owned(),
+                source: e,
+            })?;
+            // Don't echo lines of the secrets file into the logs
+            toml::from_str(&src).map_err(|e| ConfigError::parse(p, &src, false, e))
+        };
         if conf.settings.use_env_secrets {
-            erxit("use_env_secrets functionality is not available yet")
+            // The secrets file is optional here, but still read if given
+            let mut secrets = if let Some(ref p) = conf.settings.secrets_file {
+                file_secrets(p)?
+            } else {
+                Secrets::default()
+            };
+            if let Some(password) = env_secret("FRQ_DB_PASSWORD")? {
+                secrets.db = Some(DbSecrets { password });
+            }
+            if let Some(secret_key) = env_secret("FRQ_SESSION_SECRET_KEY")? {
+                secrets.session = Some(SessionSecrets { secret_key });
+            }
+            if let Some(smtp_password) = env_secret("FRQ_SMTP_PASSWORD")? {
+                secrets.mail = Some(MailSecrets { smtp_password });
+            }
+            if let Some(encryption_key) = env_secret("FRQ_TOTP_ENCRYPTION_KEY")? {
+                secrets.totp = Some(TotpSecrets { encryption_key });
+            }
+            Ok(secrets)
         } else if let Some(ref p) = conf.settings.secrets_file {
-            Ok(toml::from_str(&std::fs::read_to_string(p)?)?)
+            file_secrets(p)
         } else {
-            erxit("specified secrets file does not exist!")
+            Err(ConfigError::NoSecretsSource)
+        }
+    }
+}
+
+// Reads `var`, falling back to the contents of the file named by `var`_FILE
+fn env_secret(var: &str) -> Result<Option<String>, ConfigError> {
+    lookup_secret(var, |v| env::var(v).ok())
+}
+
+// As `env_secret`, with variables read by `lookup`
+fn lookup_secret(
+    var: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Option<String>, ConfigError> {
+    let file_var = format!("{}_FILE", var);
+    let direct = lookup(var);
+    let file = lookup(&file_var);
+    if let Some(v) = direct {
+        if file.is_some() {
+            warn!("both {} and {} are set; using {}", var, file_var, var);
+        }
+        trace!("read secret from {}", var);
+        return Ok(Some(v));
+    }
+    if let Some(p) = file {
+        let v = std::fs::read_to_string(&p).map_err(|e| ConfigError::SecretFile {
+            var: file_var.clone(),
+            path: p.clone(),
+            source: e,
+        })?;
+        trace!("read secret from {} ({})", file_var, p);
+        // Secret files are commonly written with a trailing newline
+        return Ok(Some(v.trim_end_matches(['\r', '\n']).to_owned()));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    const MINIMAL: &str = r#"
+[settings]
+use_env_secrets = false
+port = 4433
+
+[db]
+dbms = "sqlite"
+username = ""
+database = "fastrequest.db"
+address = ""
+port = 0
+"#;
+
+    // A fresh file under the system temp directory, unique to this process
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("frq-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_minimal_config() {
+        let path = temp_file("minimal.toml", MINIMAL);
+        let conf = Config::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(conf.db.dbms, Dbms::Sqlite);
+        assert_eq!(conf.settings.port, 4433);
+    }
+
+    #[test]
+    fn missing_config_file() {
+        let path = env::temp_dir().join("frq-config-test-does-not-exist.toml");
+        let err = Config::from_file(path.to_str().unwrap())
+            .err()
+            .expect("an error");
+        assert!(matches!(err, ConfigError::Read { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn parse_error_has_location() {
+        let src = "[settings]\nuse_env_secrets = false\nport = \"4433\"\n";
+        let err = Config::parse(src, "test.toml").err().expect("an error");
+        match err {
+            ConfigError::Parse {
+                ref path,
+                location,
+                ref snippet,
+                ..
+            } => {
+                assert_eq!(path, "test.toml");
+                assert_eq!(location, Some((3, 8)));
+                assert_eq!(snippet.as_deref(), Some("port = \"4433\""));
+            }
+            _ => panic!("expected a parse error, got {:?}", err),
+        }
+        assert!(err.diagnostic().contains("--> test.toml:3:8"));
+    }
+
+    #[test]
+    fn missing_referenced_file() {
+        let missing = env::temp_dir().join("frq-config-test-missing.pem");
+        let src = format!(
+            "{}\n[ssl]\ncert = \"{}\"\nkey = \"{}\"\n",
+            MINIMAL,
+            missing.display(),
+            missing.display()
+        );
+        match Config::parse(&src, "test.toml").err().expect("an error") {
+            ConfigError::MissingFile { key, path } => {
+                assert_eq!(key, "ssl.cert");
+                assert_eq!(path, missing.display().to_string());
+            }
+            err => panic!("expected a missing file error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn unsupported_dbms() {
+        let src = MINIMAL.replace("\"sqlite\"", "\"oracle\"");
+        match Config::parse(&src, "test.toml").err().expect("an error") {
+            ConfigError::Parse {
+                location, message, ..
+            } => {
+                assert_eq!(location.map(|(line, _)| line), Some(7));
+                assert!(message.contains("oracle"), "{}", message);
+            }
+            err => panic!("expected a parse error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn credentials_with_any_origin() {
+        let src = format!(
+            "{}\n[cors]\nallowed_origins = [\"*\"]\nallow_credentials = true\n",
+            MINIMAL
+        );
+        match Config::parse(&src, "test.toml").err().expect("an error") {
+            ConfigError::Invalid { key, .. } => assert_eq!(key, "cors.allow_credentials"),
+            err => panic!("expected an invalid setting error, got {:?}", err),
+        }
+        let src = src.replace("\"*\"", "\"https://app.example.org\"");
+        assert!(Config::parse(&src, "test.toml").is_ok());
+    }
+
+    #[test]
+    fn token_lifetimes() {
+        for (setting, key) in [
+            (
+                "verification_token_hours",
+                "accounts.verification_token_hours",
+            ),
+            (
+                "password_reset_token_minutes",
+                "accounts.password_reset_token_minutes",
+            ),
+        ] {
+            for value in ["0", "-1", "9223372036854775807"] {
+                let src = format!("{}\n[accounts]\n{} = {}\n", MINIMAL, setting, value);
+                match Config::parse(&src, "test.toml").err().expect("an error") {
+                    ConfigError::Invalid { key: k, .. } => assert_eq!(k, key),
+                    err => panic!("expected an invalid setting error, got {:?}", err),
+                }
+            }
+            let src = format!("{}\n[accounts]\n{} = 24\n", MINIMAL, setting);
+            assert!(Config::parse(&src, "test.toml").is_ok());
+        }
+    }
+
+    #[test]
+    fn no_secrets_source() {
+        let conf = Config::parse(MINIMAL, "test.toml").unwrap();
+        assert!(matches!(
+            Secrets::new(&conf),
+            Err(ConfigError::NoSecretsSource)
+        ));
+    }
+
+    #[test]
+    fn missing_secret() {
+        let src = MINIMAL.replace("\"sqlite\"", "\"postgres\"");
+        let conf = Config::parse(&src, "test.toml").unwrap();
+        assert!(matches!(
+            crate::dbms::get_url(&conf, &Secrets::default()),
+            Err(ConfigError::MissingSecret("db.password"))
+        ));
+    }
+
+    #[test]
+    fn secrets_file_is_not_echoed() {
+        let path = temp_file("secrets.toml", "[db]\npassword = hunter2\n");
+        let src = MINIMAL.replace(
+            "use_env_secrets = false",
+            &format!(
+                "secrets_file = \"{}\"\nuse_env_secrets = false",
+                path.display()
+            ),
+        );
+        let conf = Config::parse(&src, "test.toml").unwrap();
+        let err = Secrets::new(&conf).err().expect("invalid secrets file");
+        assert!(matches!(err, ConfigError::Parse { snippet: None, .. }));
+        assert!(!err.diagnostic().contains("hunter2"));
+    }
+
+    #[test]
+    fn secret_files() {
+        let var = "FRQ_TEST_SECRET";
+        let path = temp_file("secret", "hunter2\r\n");
+        let file =
+            |name: &str| (name == "FRQ_TEST_SECRET_FILE").then(|| path.display().to_string());
+        // Trailing newlines are dropped
+        assert_eq!(
+            lookup_secret(var, file).unwrap().as_deref(),
+            Some("hunter2")
+        );
+        // The variable itself wins over its _FILE
+        let both = |name: &str| match name {
+            "FRQ_TEST_SECRET" => Some("direct".to_owned()),
+            _ => file(name),
+        };
+        assert_eq!(lookup_secret(var, both).unwrap().as_deref(), Some("direct"));
+        assert_eq!(lookup_secret(var, |_| None).unwrap(), None);
+    }
+
+    #[test]
+    fn unreadable_secret_file() {
+        let var = "FRQ_TEST_SECRET";
+        let missing = env::temp_dir().join("frq-config-test-missing-secret");
+        let lookup =
+            |name: &str| (name == "FRQ_TEST_SECRET_FILE").then(|| missing.display().to_string());
+        match lookup_secret(var, lookup) {
+            Err(ConfigError::SecretFile { var: v, path, .. }) => {
+                assert_eq!(v, "FRQ_TEST_SECRET_FILE");
+                assert_eq!(path, missing.display().to_string());
+            }
+            other => panic!("expected a secret file error, got {:?}", other.err()),
         }
     }
 }
@@ -1 +1,17 @@
 pub static ETC_CONFIG_TARGET: &'static str = "/etc/fastrequest.toml";
+pub static SESSION_COOKIE: &'static str = "frq_session";
+pub const SESSION_LIFETIME_HOURS: i64 = 24 * 7;
+// Time to enter the second factor after the password checked out
+pub const PENDING_LOGIN_MINUTES: i64 = 5;
+// First line of certificates written by `fastrequest dev-cert`
+pub static DEV_CERT_MARKER: &'static str = "# FastRequest self-signed development certificate";
+// PE = People's Edition, for sending requests to lots of agencies
+// GE = Government Edition, for agencies
+pub static EDITION: &'static str = "PE";
+// CachedCompression keeps compressed copies of these paths in memory
+pub static COMPRESSION_CACHED_PATHS: [&'static str; 3] = ["", "/", "/index.html"];
+pub static COMPRESSION_CACHED_SUFFIXES: [&'static str; 6] =
+    [".js", ".css", ".html", ".png", ".jpg", ".svg"];
+pub static COMPRESSION_EXCLUDED_PREFIXES: [&'static str; 1] = ["/api/"];
+// Accepted from clients/proxies and echoed in responses
+pub static REQUEST_ID_HEADER: &'static str = "X-Request-Id";
@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::config::CorsConfig;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Method, Status},
+    Request, Response,
+};
+
+enum OriginPattern {
+    // "*"
+    Any,
+    // "https://app.example.org"
+    Exact(String),
+    // "https://*.example.org" is stored as ("https://", ".example.org")
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    fn parse(s: &str) -> Self {
+        let s = s.trim().trim_end_matches('/').to_ascii_lowercase();
+        if s == "*" {
+            return OriginPattern::Any;
+        }
+        match s.split_once("://*.") {
+            Some((scheme, rest)) => OriginPattern::Subdomains {
+                scheme: format!("{}://", scheme),
+                suffix: format!(".{}", rest),
+            },
+            None => OriginPattern::Exact(s),
+        }
+    }
+
+    // `origin` must already be lowercase
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(o) => o == origin,
+            OriginPattern::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+                .is_some_and(|sub| {
+                    !sub.is_empty()
+                        && sub
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                }),
+        }
+    }
+}
+
+// Adds CORS headers to responses for allowed origins, answers preflights
+// (together with the `preflight` route), and sets COOP/COEP everywhere
+// except the configured exempt paths.
+pub struct Cors {
+    origins: Vec<OriginPattern>,
+    methods: String,
+    headers: String,
+    credentials: bool,
+    max_age: String,
+    isolation_exempt_paths: Vec<String>,
+}
+
+impl Cors {
+    // `fallback_origin` (settings.url) is used if no origins are configured
+    pub fn new(conf: &CorsConfig, fallback_origin: Option<&str>) -> Self {
+        let mut origins: Vec<OriginPattern> = conf
+            .allowed_origins
+            .iter()
+            .map(|o| OriginPattern::parse(o))
+            .collect();
+        if origins.is_empty() {
+            origins.extend(fallback_origin.map(OriginPattern::parse));
+        }
+        Cors {
+            origins,
+            methods: conf.allowed_methods.join(", "),
+            headers: conf.allowed_headers.join(", "),
+            credentials: conf.allow_credentials,
+            max_age: conf.max_age.to_string(),
+            isolation_exempt_paths: conf.isolation_exempt_paths.clone(),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.origins.iter().any(|o| o.matches(&origin))
+    }
+
+    // Config loading rejects credentials with "*", but an origin only
+    // "*" allows never gets them regardless
+    fn allows_credentials(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.credentials
+            && self
+                .origins
+                .iter()
+                .any(|o| !matches!(o, OriginPattern::Any) && o.matches(&origin))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS/COEP",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let path = request.uri().path();
+        if !self
+            .isolation_exempt_paths
+            .iter()
+            .any(|p| path.as_str().starts_with(p.as_str()))
+        {
+            response.set_header(Header::new("Cross-Origin-Opener-Policy", "same-origin"));
+            response.set_header(Header::new("Cross-Origin-Embedder-Policy", "require-corp"));
+        }
+
+        // The answer depends on Origin, so caches must key on it
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if !self.allows(origin) {
+            debug!("CORS: origin {} is not allowed", origin);
+            return;
+        }
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_owned(),
+        ));
+        if self.allows_credentials(origin) {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        let preflight = request.method() == Method::Options
+            && request.headers().contains("Access-Control-Request-Method");
+        if preflight {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                self.methods.clone(),
+            ));
+            response.set_header(Header::new(
+                "Access-Control-Allow-Headers",
+                self.headers.clone(),
+            ));
+            response.set_header(Header::new("Access-Control-Max-Age", self.max_age.clone()));
+            response.adjoin_header(Header::new("Vary", "Access-Control-Request-Method"));
+            response.adjoin_header(Header::new("Vary", "Access-Control-Request-Headers"));
+        }
+    }
+}
+
+// Gives preflights a 204 to attach the headers above to. Origins that
+// aren't allowed get the 204 too, but without any CORS headers.
+#[options("/<_..>")]
+pub fn preflight() -> Status {
+    Status::NoContent
+}
@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Self-signed certificates for local development; see `fastrequest dev-cert`
+
+use crate::consts::*;
+
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Always valid for these; extra names are added on top
+const DEFAULT_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+// Writes a certificate for localhost (plus `extra_hosts`) and its key.
+// Existing files are only replaced when `force` is set.
+pub fn generate(
+    cert_path: &str,
+    key_path: &str,
+    extra_hosts: &[String],
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !force {
+        for p in [cert_path, key_path] {
+            if Path::new(p).exists() {
+                return Err(format!("{} already exists; pass --force to replace it", p).into());
+            }
+        }
+    }
+    let mut hosts: Vec<String> = DEFAULT_HOSTS.iter().map(|h| h.to_string()).collect();
+    hosts.extend(extra_hosts.iter().cloned());
+    let certified = rcgen::generate_simple_self_signed(hosts.clone())?;
+
+    for p in [cert_path, key_path] {
+        if let Some(dir) = Path::new(p).parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    // The marker line sits outside the PEM block, where parsers ignore it
+    fs::write(
+        cert_path,
+        format!("{}\n{}", DEV_CERT_MARKER, certified.cert.pem()),
+    )?;
+    // Opening an existing file would keep its permissions, so the key is
+    // always written to a new one
+    match fs::remove_file(key_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    opts.open(key_path)?
+        .write_all(certified.key_pair.serialize_pem().as_bytes())?;
+    info!("generated development certificate for {}", hosts.join(", "));
+    Ok(())
+}
+
+// Whether `cert_path` holds a certificate made by `generate`
+pub fn is_dev_cert(cert_path: &str) -> bool {
+    fs::read_to_string(cert_path).is_ok_and(|s| s.starts_with(DEV_CERT_MARKER))
+}
+
+// Development certificates are untrusted by browsers and their key sits
+// next to the source tree; say so as loudly as possible
+pub fn warn_release() {
+    if !cfg!(debug_assertions) {
+        warn!("********************************************************************");
+        warn!("* This is a release build using a self-signed development          *");
+        warn!("* certificate. Clients will not trust it. Configure a real         *");
+        warn!("* certificate in [ssl], or terminate TLS at a reverse proxy.       *");
+        warn!("********************************************************************");
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Logging setup. A default logger is installed first thing so config
+// errors can be reported; `configure` then swaps in the [logging] settings.
+// RUST_LOG, if set, overrides the configured levels.
+
+use crate::auth;
+use crate::config::{LogFormat, LoggingConfig};
+use crate::request_id::{RequestId, RequestStart};
+
+use std::env;
+use std::io::Write;
+use std::sync::{OnceLock, RwLock};
+
+use env_logger::fmt::Formatter;
+use log::kv::{Key, Value, VisitSource};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn, LevelFilter, Log, Metadata, Record};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+use serde_json::{Map, Value as Json};
+
+// Target of the access log lines; filterable like a module
+pub const ACCESS_TARGET: &str = "access";
+
+struct Switch(RwLock<env_logger::Logger>);
+
+impl Log for Switch {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+static LOGGER: OnceLock<Switch> = OnceLock::new();
+
+fn default_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Warn
+    }
+}
+
+fn install(logger: env_logger::Logger) {
+    log::set_max_level(logger.filter());
+    match LOGGER.get() {
+        Some(switch) => *switch.0.write().unwrap() = logger,
+        None => {
+            let switch = LOGGER.get_or_init(|| Switch(RwLock::new(logger)));
+            log::set_logger(switch).expect("logger is only installed here");
+        }
+    }
+}
+
+// Pretty output at the build's default level, until `configure` runs
+pub fn init() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(default_level());
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse_filters(&spec);
+    }
+    install(builder.build());
+}
+
+pub fn configure(conf: &LoggingConfig) {
+    let mut builder = match conf.format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder(),
+        LogFormat::Json => {
+            let mut b = env_logger::Builder::new();
+            b.format(write_json);
+            b
+        }
+    };
+    builder.filter_level(conf.level.unwrap_or_else(default_level));
+    if conf.access_log {
+        builder.filter_module(ACCESS_TARGET, LevelFilter::Info);
+    }
+    for (module, level) in &conf.modules {
+        builder.filter_module(module, *level);
+    }
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse_filters(&spec);
+    }
+    install(builder.build());
+    trace!("logging configured");
+}
+
+// Collects a record's key-values as JSON fields
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let v = if let Some(n) = value.to_u64() {
+            Json::from(n)
+        } else if let Some(n) = value.to_f64() {
+            Json::from(n)
+        } else if let Some(b) = value.to_bool() {
+            Json::from(b)
+        } else {
+            Json::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), v);
+        Ok(())
+    }
+}
+
+// One object per line, e.g. for Loki
+fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut line = Map::new();
+    line.insert(
+        "ts".to_owned(),
+        Json::from(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    );
+    line.insert("level".to_owned(), Json::from(record.level().as_str()));
+    line.insert("target".to_owned(), Json::from(record.target()));
+    line.insert("message".to_owned(), Json::from(record.args().to_string()));
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    writeln!(buf, "{}", Json::Object(line))
+}
+
+// One line per response: method, path, status, latency, request and
+// user ID. Attach after the fairings that may change the status.
+pub struct AccessLog;
+
+#[rocket::async_trait]
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if !log::log_enabled!(target: ACCESS_TARGET, log::Level::Info) {
+            return;
+        }
+        let latency_ms = RequestStart::of(req).elapsed().as_secs_f64() * 1000.0;
+        let method = req.method().as_str();
+        let path = req.uri().path().to_string();
+        let status = res.status().code;
+        let request_id = RequestId::of(req).0.as_str();
+        let user_id = auth::cached_user_id(req).map_or(String::new(), |id| id.to_string());
+        info!(
+            target: ACCESS_TARGET,
+            method = method,
+            path = path.as_str(),
+            status = status,
+            latency_ms = latency_ms,
+            request_id = request_id,
+            user_id = user_id.as_str();
+            "{} {} {} {:.1}ms id={}{}{}",
+            method,
+            path,
+            status,
+            latency_ms,
+            request_id,
+            if user_id.is_empty() { "" } else { " user=" },
+            user_id
+        );
+    }
+}
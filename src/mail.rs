@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Outbound email. SMTP for production; file and stdout transports let
+// development setups and tests read the mail (and its links) instead.
+
+use crate::config::{Config, MailTransport, Secrets, SmtpTls};
+
+use std::error::Error;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+pub type MailResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone)]
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    // One .eml file per message
+    File(AsyncFileTransport<Tokio1Executor>),
+    Stdout,
+}
+
+// Managed state; cheap to clone, for sending from spawned tasks
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Transport,
+    from: Mailbox,
+    // settings.url, for building links back to the app
+    base_url: String,
+}
+
+impl Mailer {
+    pub fn new(conf: &Config, secrets: &Secrets) -> Result<Self, Box<dyn Error>> {
+        let mail = &conf.mail;
+        let transport = match mail.transport {
+            MailTransport::Smtp => {
+                let smtp = &mail.smtp;
+                let mut builder = match smtp.tls {
+                    SmtpTls::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+                    }
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+                    SmtpTls::None => {
+                        warn!("SMTP without TLS; mail and credentials are sent in plain text");
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+                    }
+                };
+                if let Some(port) = smtp.port {
+                    builder = builder.port(port);
+                }
+                if let Some(ref username) = smtp.username {
+                    let password = secrets
+                        .mail
+                        .as_ref()
+                        .map(|m| m.smtp_password.clone())
+                        .ok_or("mail.smtp.username is set, but no smtp_password is in secrets")?;
+                    builder = builder.credentials(Credentials::new(username.clone(), password));
+                }
+                Transport::Smtp(builder.build())
+            }
+            MailTransport::File => {
+                std::fs::create_dir_all(&mail.directory)?;
+                info!("writing outgoing mail to {}", mail.directory);
+                Transport::File(AsyncFileTransport::new(&mail.directory))
+            }
+            MailTransport::Stdout => {
+                info!("printing outgoing mail to stdout");
+                Transport::Stdout
+            }
+        };
+        let base_url = conf
+            .settings
+            .url
+            .clone()
+            .unwrap_or_else(|| format!("https://localhost:{}", conf.settings.port));
+        Ok(Mailer {
+            transport,
+            from: mail.from.parse()?,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    // Absolute URL of `path` (which starts with '/') in the web app
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> MailResult {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        match self.transport {
+            Transport::Smtp(ref t) => {
+                t.send(message).await?;
+            }
+            Transport::File(ref t) => {
+                t.send(message).await?;
+            }
+            Transport::Stdout => {
+                println!("{}", String::from_utf8_lossy(&message.formatted()));
+            }
+        }
+        debug!("sent \"{}\" to {}", subject, to);
+        Ok(())
+    }
+}
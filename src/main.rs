@@ -3,48 +3,71 @@
 #[macro_use]
 extern crate rocket;
 
+mod api;
+mod auth;
+mod certs;
+mod cli;
 mod config;
 mod consts;
+mod cors;
 mod dbms;
+mod devcert;
 mod entities;
+mod logging;
+mod mail;
+mod metrics;
 mod migrator;
+mod password_policy;
+mod proxy;
+mod ratelimit;
+mod request_id;
+mod scaffold;
+mod security;
+mod tokens;
+mod totp;
+mod users;
 mod utils;
+mod webauthn;
 
 use utils::*;
 
 use std::env;
+use std::path::PathBuf;
 
+use clap::Parser;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use rocket::{
-    config::TlsConfig,
-    fairing::{Fairing, Info, Kind},
+    fairing::AdHoc,
     figment::Figment,
     fs::{relative, FileServer},
-    http::Header,
     response::content::RawHtml,
-    shield::{Hsts, Shield},
-    time::Duration,
-    Build, Request, Response, Rocket, State,
+    tls::{Resolver, TlsConfig},
+    Build, Request, Rocket, State,
 };
 use rocket_async_compression::CachedCompression;
 use sea_orm_rocket::Database;
 
-#[launch]
-async fn rocket_main() -> Rocket<Build> {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var(
-            "RUST_LOG",
-            if cfg!(debug_assertions) {
-                "info"
-            } else {
-                "warn"
-            },
-        );
-    }
-    pretty_env_logger::init();
+#[rocket::main]
+async fn main() {
+    logging::init();
     trace!("initialized logger");
 
+    match cli::Cli::parse().command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => {
+            if let Err(e) = rocket_main().launch().await {
+                erxit(&format!("server failed: {}", e));
+            }
+        }
+        cmd => {
+            if let Err(e) = cli::run(cmd).await {
+                erxit(&e.to_string());
+            }
+        }
+    }
+}
+
+fn rocket_main() -> Rocket<Build> {
     // NOTE: Nix builds will need to pass FRQ_BUILD_DIST
     // Find dist/ folder with Svelte compilation results
     let runtime_dist = env::var("FRQ_RUNTIME_DIST");
@@ -67,93 +90,210 @@ async fn rocket_main() -> Rocket<Build> {
         p
     };
     debug!("located dist at {}", dist);
+    let dist_holder = DistHolder::load(dist);
 
-    let conf = config::Config::load_config();
+    let conf = config::Config::load_config().unwrap_or_else(|e| erxit(&e.diagnostic()));
+    logging::configure(&conf.logging);
     trace!("configuration loaded");
     let secrets = config::Secrets::new(&conf).unwrap_or_else(|e| {
-        debug!("{}", e);
-        erxit("failed to load secrets")
+        error!("failed to load secrets");
+        erxit(&e.diagnostic())
     });
     trace!("secrets loaded");
+    let db_url = dbms::get_url(&conf, &secrets).unwrap_or_else(|e| erxit(&e.diagnostic()));
+    let auto_migrate = conf.db.auto_migrate;
+    let hsts = security::hsts_enabled(
+        &conf.security.headers,
+        conf.settings.url.as_deref(),
+        conf.ssl.is_some(),
+    );
 
-    // NOTE: for the future, versions of FastRequest
-    // PE = People's Edition, for sending requests to lots of agencies
-    // GE = Government Edition, for agencies
-    info!("FastRequest PE v{}", env!("CARGO_PKG_VERSION"));
+    // NOTE: for the future, versions of FastRequest; see consts::EDITION
+    info!(
+        "FastRequest {} v{} ({})",
+        consts::EDITION,
+        env!("CARGO_PKG_VERSION"),
+        env!("FRQ_GIT_HASH")
+    );
     info!("Copyright (c) 2024 Open Information Collective, licensed under AGPLv3");
 
-    let terminal_url_link: String = format!("https://[::]:{}", conf.settings.port);
+    let trusted_proxies = proxy::TrustedProxies::new(&conf.settings.trusted_proxies)
+        .unwrap_or_else(|e| erxit(&format!("settings.trusted_proxies: {}", e)));
+
+    let terminal_url_link: String = format!(
+        "{}://{}",
+        if conf.ssl.is_some() { "https" } else { "http" },
+        std::net::SocketAddr::new(conf.settings.address, conf.settings.port)
+    );
     // CORS fairings (accept types, accurate clock)
     // Shared state for database
     info!(
         "Launching Rocket server on {}",
         terminal_link::Link::new(&terminal_url_link, &terminal_url_link)
     );
-    info!("Using protocols HTTP3/udp, HTTP2/tcp, HTTP1.1/tcp");
-    warn!("HTTP/3 support may throw benign errors; it is not yet stable");
-    rocket::custom(
-        Figment::from(rocket::Config::release_default())
-            .merge(("tls", TlsConfig::from_paths(&conf.ssl.cert, &conf.ssl.key)))
-            .merge(("port", conf.settings.port))
-            .merge(("address", "::".parse::<std::net::IpAddr>().unwrap()))
-            .merge(("databases.fastrequest.url", dbms::get_url(&conf, &secrets))),
-    )
-    .attach(dbms::Db::init())
-    .attach(Shield::default().enable(Hsts::Preload(Duration::days(730))))
-    .attach(CORS {
-        url: conf.settings.url,
-    })
-    .attach(CachedCompression {
-        cached_paths: vec!["".to_owned(), "/".to_owned(), "/index.html".to_owned()],
-        cached_path_suffixes: vec![
-            ".js".to_owned(),
-            ".css".to_owned(),
-            ".html".to_owned(),
-            ".png".to_owned(),
-            ".jpg".to_owned(),
-            ".svg".to_owned(),
-        ],
-        excluded_path_prefixes: vec!["/api/".to_string()],
-        ..Default::default()
-    })
-    // TODO: .register("/", catchers![not_found, ...])
-    // TODO: .attach(AdHoc::try_on_ignite("Migrations", run_migrations))
-    .mount("/", FileServer::from(dist))
-    .mount("/", routes![index])
-    .manage(dist.to_string() as DistHolder)
+    let figment = Figment::from(rocket::Config::release_default())
+        .merge(("port", conf.settings.port))
+        .merge(("address", conf.settings.address))
+        // Client addresses come from proxy::ClientIp, which only trusts
+        // forwarding headers from settings.trusted_proxies
+        .merge(("ip_header", false))
+        .merge(("databases.fastrequest.url", db_url))
+        .merge(("databases.fastrequest.pool", &conf.db.pool));
+    let figment = if let Some(ref ssl) = conf.ssl {
+        if devcert::is_dev_cert(&ssl.cert) {
+            devcert::warn_release();
+        }
+        info!("Using protocols HTTP3/udp, HTTP2/tcp, HTTP1.1/tcp");
+        warn!("HTTP/3 support may throw benign errors; it is not yet stable");
+        figment
+            .merge(("tls", TlsConfig::from_paths(&ssl.cert, &ssl.key)))
+            .merge((certs::FIGMENT_KEY, ssl))
+    } else {
+        info!("No [ssl] configured; serving plain HTTP for a TLS-terminating proxy");
+        figment
+    };
+    let (figment, token_signer) = if let Some(ref s) = secrets.session {
+        (
+            figment.merge(("secret_key", &s.secret_key)),
+            tokens::TokenSigner::new(s.secret_key.as_bytes()),
+        )
+    } else {
+        warn!("no session secret_key in secrets; sessions and emailed links will not survive a restart");
+        let key = auth::ephemeral_secret_key();
+        let signer = tokens::TokenSigner::new(&key);
+        (figment.merge(("secret_key", key)), signer)
+    };
+    let mailer = mail::Mailer::new(&conf, &secrets)
+        .unwrap_or_else(|e| erxit(&format!("invalid [mail] configuration: {}", e)));
+    let password_policy = password_policy::PasswordPolicy::new(&conf.password_policy)
+        .unwrap_or_else(|e| erxit(&format!("invalid [password_policy] configuration: {}", e)));
+    let totp_cipher =
+        totp::TotpCipher::new(secrets.totp.as_ref().map(|t| t.encryption_key.as_str()));
+    let rocket = rocket::custom(figment);
+    let rocket = if conf.ssl.is_some() {
+        rocket.attach(certs::ReloadingResolver::fairing())
+    } else {
+        rocket
+    };
+    let rocket = rocket
+        .attach(dbms::Db::init())
+        .attach(AdHoc::try_on_ignite("Migrations", move |rocket| {
+            dbms::run_migrations(rocket, auto_migrate)
+        }))
+        .attach(security::shield(&conf.security.headers, hsts))
+        .attach(security::SecurityHeaders::new(
+            &conf.security.headers,
+            &dist_holder.index,
+            hsts,
+        ))
+        .attach(cors::Cors::new(&conf.cors, conf.settings.url.as_deref()))
+        .attach(CachedCompression {
+            cached_paths: consts::COMPRESSION_CACHED_PATHS.map(str::to_owned).to_vec(),
+            cached_path_suffixes: consts::COMPRESSION_CACHED_SUFFIXES
+                .map(str::to_owned)
+                .to_vec(),
+            excluded_path_prefixes: consts::COMPRESSION_EXCLUDED_PREFIXES
+                .map(str::to_owned)
+                .to_vec(),
+            ..Default::default()
+        })
+        .attach(request_id::RequestIds)
+        .attach(logging::AccessLog)
+        .register("/", catchers![spa_not_found])
+        .register("/api", api::catchers())
+        .mount("/", FileServer::from(dist))
+        .mount("/", routes![index, spa_fallback, cors::preflight])
+        .mount("/api", api::routes())
+        .manage(dist_holder)
+        .manage(trusted_proxies)
+        .manage(token_signer)
+        .manage(mailer)
+        .manage(ratelimit::PasswordResetLimits {
+            per_email: ratelimit::RateLimiter::new(
+                conf.accounts.password_reset_per_email,
+                std::time::Duration::from_secs(3600),
+            ),
+            per_ip: ratelimit::RateLimiter::new(
+                conf.accounts.password_reset_per_ip,
+                std::time::Duration::from_secs(3600),
+            ),
+        })
+        .manage(ratelimit::SecondFactorLimits {
+            login: ratelimit::RateLimiter::new(
+                conf.accounts.second_factor_per_user,
+                std::time::Duration::from_secs(3600),
+            ),
+            account: ratelimit::RateLimiter::new(
+                conf.accounts.second_factor_per_user,
+                std::time::Duration::from_secs(3600),
+            ),
+        })
+        .manage(ratelimit::PasskeyLoginLimits {
+            per_ip: ratelimit::RateLimiter::new(
+                conf.accounts.passkey_login_per_ip,
+                webauthn::TIMEOUT,
+            ),
+        })
+        .manage(totp_cipher)
+        .manage(password_policy)
+        .manage(webauthn::Passkeys::new(
+            conf.settings.url.as_deref(),
+            conf.settings.port,
+        ))
+        .manage(conf.accounts.clone());
+    // Last, so it sees the final status and Content-Encoding
+    if conf.metrics.enabled {
+        rocket.attach(metrics::Metrics::new(&conf.metrics))
+    } else {
+        rocket
+    }
 }
 
-struct CORS {
-    url: Option<String>,
+// The Svelte build output, with index.html read once at startup
+struct DistHolder {
+    root: PathBuf,
+    index: String,
 }
 
-#[rocket::async_trait]
-impl Fairing for CORS {
-    fn info(&self) -> Info {
-        Info {
-            name: "CORS/COEP",
-            kind: Kind::Response,
+impl DistHolder {
+    fn load(path: &str) -> Self {
+        let index = std::fs::read_to_string([path, "index.html"].join("/"))
+            .unwrap_or_else(|_| erxit("dist folder has no readable index.html"));
+        DistHolder {
+            root: PathBuf::from(path),
+            index,
         }
     }
+}
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        if let Some(ref url) = self.url {
-            response.set_header(Header::new("Access-Control-Allow-Origin", url.clone()));
-        }
-        response.set_header(Header::new(
-            "Access-Control-Allow-Methods",
-            "POST, PUT, DELETE, HEAD, GET, OPTIONS",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "false"));
-        response.set_header(Header::new("Cross-Origin-Opener-Policy", "same-origin"));
-        response.set_header(Header::new("Cross-Origin-Embedder-Policy", "require-corp"));
-    }
+#[get("/")]
+fn index(dist: &State<DistHolder>) -> RawHtml<String> {
+    RawHtml(dist.index.clone())
 }
 
-type DistHolder = String;
+// History-mode routing: deep links such as /requests/123 are client-side
+// routes, so browsers get the app for any path FileServer (rank 10) didn't
+// serve. API paths, files FileServer declined (e.g. hidden ones) and
+// non-HTML requests fall through to the 404 catchers instead.
+#[get("/<path..>", format = "text/html", rank = 20)]
+async fn spa_fallback(path: PathBuf, dist: &State<DistHolder>) -> Option<RawHtml<String>> {
+    if path.starts_with("api") {
+        return None;
+    }
+    // A dot alone doesn't make a file: /requests/foia.2024 is a route
+    if rocket::tokio::fs::metadata(dist.root.join(&path))
+        .await
+        .is_ok_and(|m| m.is_file())
+    {
+        return None;
+    }
+    Some(RawHtml(dist.index.clone()))
+}
 
-#[get("/")]
-fn index(dist: &State<DistHolder>) -> RawHtml<Option<String>> {
-    RawHtml(std::fs::read_to_string([&*dist, "index.html"].join("/")).ok())
+// Outside /api, unknown paths get the Svelte app so it can show its own page
+#[catch(404)]
+fn spa_not_found(req: &Request<'_>) -> Option<RawHtml<String>> {
+    req.rocket()
+        .state::<DistHolder>()
+        .map(|dist| RawHtml(dist.index.clone()))
 }
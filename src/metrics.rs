@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Prometheus metrics: request counts and latencies per route, database pool
+// usage and compression cache use, exposed in text format at /metrics.
+//
+// rocket_async_compression keeps its cache to itself, so a true hit/miss
+// count would need patching it. Instead, compressed responses on the paths
+// CachedCompression caches are counted as the first or a repeat for their
+// path and encoding; repeats are the responses the cache can serve.
+
+use crate::auth;
+use crate::config::MetricsConfig;
+use crate::consts::*;
+use crate::dbms::{self, Db};
+use crate::request_id::RequestStart;
+
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    figment::Figment,
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    Build, Orbit, Request, Response, Rocket,
+};
+use sea_orm::DatabaseConnection;
+use sea_orm_rocket::Database;
+
+struct Collectors {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    pool_open: IntGauge,
+    pool_idle: IntGauge,
+    compression: IntCounterVec,
+    // (path, encoding) pairs seen compressed on cached paths
+    compressed: Mutex<HashSet<(String, String)>>,
+    // Set at liftoff; read at scrape time for the pool gauges
+    db: OnceLock<DatabaseConnection>,
+}
+
+impl Collectors {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("fastrequest".to_owned()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from receiving a request to sending its response headers",
+            ),
+            &["method", "route"],
+        )?;
+        let pool_open = IntGauge::new("db_pool_connections", "Open database connections")?;
+        let pool_idle = IntGauge::new("db_pool_idle_connections", "Idle database connections")?;
+        let compression = IntCounterVec::new(
+            Opts::new(
+                "compression_cache_total",
+                "Compressed responses on cached paths, by whether the path and encoding were served before",
+            ),
+            &["seen", "encoding"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(pool_open.clone()))?;
+        registry.register(Box::new(pool_idle.clone()))?;
+        registry.register(Box::new(compression.clone()))?;
+        Ok(Collectors {
+            registry,
+            requests,
+            latency,
+            pool_open,
+            pool_idle,
+            compression,
+            compressed: Mutex::new(HashSet::new()),
+            db: OnceLock::new(),
+        })
+    }
+}
+
+// Shared by the main server and the separate metrics listener
+fn collectors() -> &'static Collectors {
+    static COLLECTORS: OnceLock<Collectors> = OnceLock::new();
+    COLLECTORS.get_or_init(|| Collectors::new().expect("metric definitions are valid"))
+}
+
+// Mirrors the path selection of CachedCompression in main.rs
+fn compression_cached(path: &str) -> bool {
+    !COMPRESSION_EXCLUDED_PREFIXES
+        .iter()
+        .any(|p| path.starts_with(p))
+        && (COMPRESSION_CACHED_PATHS.contains(&path)
+            || COMPRESSION_CACHED_SUFFIXES
+                .iter()
+                .any(|s| path.ends_with(s)))
+}
+
+// Records every request; attach last so the final status and
+// Content-Encoding are seen.
+// Mounts /metrics on the main server unless [metrics] listen is set, in
+// which case a separate server is started for it at liftoff.
+pub struct Metrics {
+    conf: MetricsConfig,
+    listener: Mutex<Option<Rocket<Build>>>,
+}
+
+impl Metrics {
+    pub fn new(conf: &MetricsConfig) -> Self {
+        let listener = conf.listen.map(|addr| {
+            let figment = Figment::from(rocket::Config::release_default())
+                .merge(("address", addr.ip()))
+                .merge(("port", addr.port()))
+                .merge(("ip_header", false))
+                // Release builds refuse to launch without one; nothing
+                // here uses private cookies, so any key will do
+                .merge(("secret_key", auth::ephemeral_secret_key()));
+            rocket::custom(figment)
+                .mount("/", routes![scrape])
+                .manage(Token(conf.bearer_token.clone()))
+        });
+        if conf.bearer_token.is_none() && conf.listen.is_none() {
+            warn!("/metrics is served on the main port without a bearer_token");
+        }
+        Metrics {
+            conf: conf.clone(),
+            listener: Mutex::new(listener),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Ignite | Kind::Liftoff | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        if self.conf.listen.is_some() {
+            return Ok(rocket);
+        }
+        Ok(rocket
+            .mount("/", routes![scrape])
+            .manage(Token(self.conf.bearer_token.clone())))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(db) = Db::fetch(rocket) {
+            let _ = collectors().db.set(db.conn().clone());
+        }
+        let listener = self.listener.lock().unwrap().take();
+        if let Some(listener) = listener {
+            rocket::tokio::spawn(async move {
+                if let Err(e) = listener.launch().await {
+                    error!("metrics listener failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let c = collectors();
+        let method = req.method().as_str();
+        // Route URIs rather than paths, to keep label cardinality bounded
+        let route = req
+            .route()
+            .map_or("unmatched".to_owned(), |r| r.uri.to_string());
+        c.requests
+            .with_label_values(&[method, &route, &res.status().code.to_string()])
+            .inc();
+        c.latency
+            .with_label_values(&[method, &route])
+            .observe(RequestStart::of(req).elapsed().as_secs_f64());
+
+        // Only successes, so the set stays bounded by the files served
+        let path = req.uri().path();
+        if res.status() != Status::Ok || !compression_cached(path.as_str()) {
+            return;
+        }
+        if let Some(encoding) = res.headers().get_one("Content-Encoding") {
+            let key = (path.to_string(), encoding.to_owned());
+            let first = c.compressed.lock().unwrap().insert(key);
+            c.compression
+                .with_label_values(&[if first { "first" } else { "repeat" }, encoding])
+                .inc();
+        }
+    }
+}
+
+struct Token(Option<String>);
+
+// Checks the bearer token, if one is configured
+struct Scraper;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Scraper {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(Token(Some(token))) = req.rocket().state::<Token>() else {
+            return Outcome::Success(Scraper);
+        };
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // Constant-time, so the token can't be guessed byte by byte
+        let same = given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+        if same {
+            Outcome::Success(Scraper)
+        } else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[get("/metrics")]
+fn scrape(_scraper: Scraper) -> Result<(ContentType, String), Status> {
+    let c = collectors();
+    if let Some((open, idle)) = c.db.get().and_then(dbms::pool_usage) {
+        c.pool_open.set(open as i64);
+        c.pool_idle.set(idle as i64);
+    }
+    TextEncoder::new()
+        .encode_to_string(&c.registry.gather())
+        .map(|body| (ContentType::Plain, body))
+        .map_err(|e| {
+            error!("failed to encode metrics: {}", e);
+            Status::InternalServerError
+        })
+}
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use sea_orm_migration::prelude::*;
+
+use crate::migrator::m20240629_000001_create_table_user::User;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20261018_000004_create_table_password_reset"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PasswordReset::Table)
+                    .col(
+                        ColumnDef::new(PasswordReset::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PasswordReset::UserId).uuid().not_null())
+                    // SHA-256 of the emailed token, hex-encoded
+                    .col(
+                        ColumnDef::new(PasswordReset::TokenHash)
+                            .string_len(64)
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordReset::ExpiryDatetime)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PasswordReset::UsedDatetime).date_time())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_userid_password_reset")
+                            .from(PasswordReset::Table, PasswordReset::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PasswordReset::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum PasswordReset {
+    Table,
+    Id,
+    UserId,
+    TokenHash,
+    ExpiryDatetime,
+    UsedDatetime,
+}
@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use sea_orm_migration::prelude::*;
+
+use crate::migrator::m20240629_000001_create_table_user::User;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20261018_000005_create_table_totp"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Totp::Table)
+                    // At most one authenticator per user
+                    .col(ColumnDef::new(Totp::UserId).uuid().not_null().primary_key())
+                    // AES-256-GCM ciphertext of the shared secret, and its nonce
+                    .col(ColumnDef::new(Totp::Secret).binary().not_null())
+                    .col(ColumnDef::new(Totp::Nonce).binary().not_null())
+                    // False until the user has entered a first code
+                    .col(
+                        ColumnDef::new(Totp::Enabled)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    // Time step of the last accepted code, against replays
+                    .col(ColumnDef::new(Totp::LastStep).big_integer())
+                    .col(ColumnDef::new(Totp::CreatedDatetime).date_time().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_userid_totp")
+                            .from(Totp::Table, Totp::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Totp::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum Totp {
+    Table,
+    UserId,
+    Secret,
+    Nonce,
+    Enabled,
+    LastStep,
+    CreatedDatetime,
+}
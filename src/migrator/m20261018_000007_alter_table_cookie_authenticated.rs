@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use sea_orm_migration::prelude::*;
+
+use crate::migrator::m20240708_000001_create_table_cookie::Cookie;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20261018_000007_alter_table_cookie_authenticated"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // False while a login still waits for its second factor;
+        // existing sessions were fully authenticated
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Cookie::Table)
+                    .add_column(
+                        ColumnDef::new(CookieAuthenticated::Authenticated)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Cookie::Table)
+                    .drop_column(CookieAuthenticated::Authenticated)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum CookieAuthenticated {
+    Authenticated,
+}
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use sea_orm_migration::prelude::*;
+
+use crate::migrator::m20240629_000001_create_table_user::User;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20261018_000008_create_table_webauthn_credential"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(WebauthnCredential::Table)
+                    .col(
+                        ColumnDef::new(WebauthnCredential::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(WebauthnCredential::UserId).uuid().not_null())
+                    // Chosen by the authenticator; at most 1023 bytes per spec
+                    .col(
+                        ColumnDef::new(WebauthnCredential::CredentialId)
+                            .var_binary(1023)
+                            .not_null()
+                            .unique_key(),
+                    )
+                    // COSE_Key as sent by the authenticator
+                    .col(
+                        ColumnDef::new(WebauthnCredential::PublicKey)
+                            .binary()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(WebauthnCredential::SignCount)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    // Comma-separated hints such as "internal,hybrid"
+                    .col(
+                        ColumnDef::new(WebauthnCredential::Transports)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    // Label chosen by the user, e.g. "Laptop"
+                    .col(ColumnDef::new(WebauthnCredential::Name).string().not_null())
+                    .col(
+                        ColumnDef::new(WebauthnCredential::CreatedDatetime)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(WebauthnCredential::LastUsedDatetime).date_time())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_userid_webauthn_credential")
+                            .from(WebauthnCredential::Table, WebauthnCredential::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(WebauthnCredential::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum WebauthnCredential {
+    Table,
+    Id,
+    UserId,
+    CredentialId,
+    PublicKey,
+    SignCount,
+    Transports,
+    Name,
+    CreatedDatetime,
+    LastUsedDatetime,
+}
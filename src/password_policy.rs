@@ -0,0 +1,441 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Server-side password rules, mirroring the checklist in
+// CreateAccountForm.svelte, plus an optional check against a local copy
+// of the Pwned Passwords list. Only SHA-1 hashes are looked up, and
+// nothing leaves the server.
+
+use crate::api::{ApiError, ApiResult};
+use crate::config::PasswordPolicyConfig;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+
+// Bounds the work a single request can make Argon2 do
+const MAX_LEN: usize = 1024;
+// Hex digits of the hash that name a range file
+const PREFIX_LEN: usize = 5;
+
+// A rule a password broke. The codes are stable, for the form to tick
+// off its checklist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    TooShort,
+    TooLong,
+    MixedCase,
+    Digit,
+    PersonalInfo,
+    Breached,
+}
+
+impl Rule {
+    pub fn code(self) -> &'static str {
+        match self {
+            Rule::TooShort => "too_short",
+            Rule::TooLong => "too_long",
+            Rule::MixedCase => "mixed_case",
+            Rule::Digit => "digit",
+            Rule::PersonalInfo => "personal_info",
+            Rule::Breached => "breached",
+        }
+    }
+
+    fn message(self, policy: &PasswordPolicy) -> String {
+        match self {
+            Rule::TooShort => format!("must be at least {} characters", policy.min_length),
+            Rule::TooLong => format!("must be at most {} characters", MAX_LEN),
+            Rule::MixedCase => "must contain an uppercase and a lowercase letter".to_owned(),
+            Rule::Digit => "must contain a number".to_owned(),
+            Rule::PersonalInfo => "must not contain your name or username".to_owned(),
+            Rule::Breached => "appears in a list of leaked passwords".to_owned(),
+        }
+    }
+}
+
+enum BreachedList {
+    // Sorted SHA-1 hashes
+    Loaded(Vec<[u8; 20]>),
+    // Directory of range files
+    Ranges(PathBuf),
+}
+
+// Managed state, also built by the command line. Cheap to clone, for
+// moving into blocking tasks.
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    require_mixed_case: bool,
+    require_digit: bool,
+    forbid_personal_info: bool,
+    breached: Option<Arc<BreachedList>>,
+}
+
+// What the form needs to mirror the policy
+#[derive(Serialize)]
+pub struct PolicyInfo {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_mixed_case: bool,
+    pub require_digit: bool,
+    pub forbid_personal_info: bool,
+    pub check_breached: bool,
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// "HASH:COUNT" (or just "HASH"), with entries of count 0 (padding in
+// range API responses) skipped
+fn parse_line(line: &str) -> Option<&str> {
+    let (hash, count) = line.trim().split_once(':').unwrap_or((line.trim(), "1"));
+    (count.trim() != "0").then_some(hash)
+}
+
+fn load_hashes(path: &Path) -> Result<Vec<[u8; 20]>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut hashes = vec![];
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+        match parse_line(&line)
+            .and_then(parse_hex)
+            .and_then(|h| <[u8; 20]>::try_from(h).ok())
+        {
+            Some(hash) => hashes.push(hash),
+            None if line.trim().is_empty() => {}
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!("skipped {} malformed lines in {}", skipped, path.display());
+    }
+    hashes.sort_unstable();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+impl PasswordPolicy {
+    pub fn new(conf: &PasswordPolicyConfig) -> Result<Self, String> {
+        if conf.min_length > MAX_LEN {
+            return Err(format!(
+                "password_policy.min_length must be at most {}",
+                MAX_LEN
+            ));
+        }
+        let breached = match conf.breached_list {
+            Some(ref p) if Path::new(p).is_dir() => {
+                info!("checking passwords against the range files in {}", p);
+                Some(Arc::new(BreachedList::Ranges(PathBuf::from(p))))
+            }
+            Some(ref p) => {
+                let hashes = load_hashes(Path::new(p))?;
+                info!(
+                    "loaded {} breached password hashes from {}",
+                    hashes.len(),
+                    p
+                );
+                Some(Arc::new(BreachedList::Loaded(hashes)))
+            }
+            None => None,
+        };
+        Ok(PasswordPolicy {
+            min_length: conf.min_length,
+            require_mixed_case: conf.require_mixed_case,
+            require_digit: conf.require_digit,
+            forbid_personal_info: conf.forbid_personal_info,
+            breached,
+        })
+    }
+
+    pub fn info(&self) -> PolicyInfo {
+        PolicyInfo {
+            min_length: self.min_length,
+            max_length: MAX_LEN,
+            require_mixed_case: self.require_mixed_case,
+            require_digit: self.require_digit,
+            forbid_personal_info: self.forbid_personal_info,
+            check_breached: self.breached.is_some(),
+        }
+    }
+
+    // Every rule `password` breaks. `personal` holds the user's names and
+    // username, none of which may appear in it (ignoring case), as in the
+    // form. May read the breached list from disk, so async code should use
+    // `enforce` instead.
+    pub fn check(&self, password: &str, personal: &[&str]) -> Vec<Rule> {
+        let mut failed = vec![];
+        let len = password.chars().count();
+        if len < self.min_length {
+            failed.push(Rule::TooShort);
+        }
+        if len > MAX_LEN {
+            // Nothing else is worth checking, or hashing
+            failed.push(Rule::TooLong);
+            return failed;
+        }
+        if self.require_mixed_case
+            && !(password.chars().any(char::is_uppercase)
+                && password.chars().any(char::is_lowercase))
+        {
+            failed.push(Rule::MixedCase);
+        }
+        if self.require_digit && !password.bytes().any(|b| b.is_ascii_digit()) {
+            failed.push(Rule::Digit);
+        }
+        if self.forbid_personal_info {
+            let lower = password.to_lowercase();
+            if personal
+                .iter()
+                .map(|p| p.trim().to_lowercase())
+                .any(|p| !p.is_empty() && lower.contains(&p))
+            {
+                failed.push(Rule::PersonalInfo);
+            }
+        }
+        if self.is_breached(password) {
+            failed.push(Rule::Breached);
+        }
+        failed
+    }
+
+    // As `check`, but on a blocking thread, and as an error on the password
+    // field listing the codes of the broken rules
+    pub async fn enforce(&self, password: &str, personal: &[&str]) -> ApiResult<()> {
+        let policy = self.clone();
+        let password = password.to_owned();
+        let personal: Vec<String> = personal.iter().map(|p| p.to_string()).collect();
+        let failed = rocket::tokio::task::spawn_blocking(move || {
+            let personal: Vec<&str> = personal.iter().map(String::as_str).collect();
+            policy.check(&password, &personal)
+        })
+        .await
+        .map_err(|e| {
+            error!("password policy task failed: {}", e);
+            ApiError::internal()
+        })?;
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let message = failed
+            .iter()
+            .map(|r| r.message(self))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Err(
+            ApiError::bad_request("weak_password", format!("password {}", message))
+                .on_field("password")
+                .with_rules(failed.iter().map(|r| r.code()).collect()),
+        )
+    }
+
+    fn is_breached(&self, password: &str) -> bool {
+        let Some(ref list) = self.breached else {
+            return false;
+        };
+        let hash: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+        match list.as_ref() {
+            BreachedList::Loaded(hashes) => hashes.binary_search(&hash).is_ok(),
+            BreachedList::Ranges(dir) => {
+                let hex: String = hash.iter().map(|b| format!("{:02X}", b)).collect();
+                match in_range_file(dir, &hex) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        // Failing open; the other rules still apply
+                        error!("failed to read breached password range: {}", e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Looks `hex` (an upper case SHA-1) up in its range file, which is small
+// (around a thousand lines), so it is read line by line
+fn in_range_file(dir: &Path, hex: &str) -> Result<bool, String> {
+    let (prefix, suffix) = hex.split_at(PREFIX_LEN);
+    let path = [format!("{}.txt", prefix), prefix.to_owned()]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|p| p.is_file());
+    let Some(path) = path else {
+        debug!("no range file for {} in {}", prefix, dir.display());
+        return Ok(false);
+    };
+    let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+        // Suffixes, or full hashes in files written that way
+        if parse_line(&line)
+            .is_some_and(|h| h.eq_ignore_ascii_case(suffix) || h.eq_ignore_ascii_case(hex))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-1 of "Passw0rd", "Tr0ub4dor" and "Unbreached9"
+    const PASSW0RD: &str = "EBFC7910077770C8340F63CD2DCA2AC1F120444F";
+    const TR0UB4DOR: &str = "60A4EDEA376BAD6F327682B1E15ACEA8BCC9E060";
+    const UNBREACHED: &str = "5B9BA8965B1A0E47526EBAD85CA3ED20DFDE3AFB";
+
+    fn policy(breached_list: Option<String>) -> PasswordPolicy {
+        PasswordPolicy::new(&PasswordPolicyConfig {
+            breached_list,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("frq-policy-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn lengths() {
+        let p = policy(None);
+        assert_eq!(p.check("Abcdef1", &[]), [Rule::TooShort]);
+        assert!(p.check("Abcdefg1", &[]).is_empty());
+        // Characters, not bytes
+        assert_eq!(p.check("Abcdé1", &[]), [Rule::TooShort]);
+        assert!(p.check("Abcdéfé1", &[]).is_empty());
+        let max = format!("Ab1{}", "x".repeat(MAX_LEN - 3));
+        assert!(p.check(&max, &[]).is_empty());
+        assert_eq!(p.check(&format!("{}x", max), &[]), [Rule::TooLong]);
+        assert!(PasswordPolicy::new(&PasswordPolicyConfig {
+            min_length: MAX_LEN + 1,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn unicode_case() {
+        let p = policy(None);
+        assert!(p.check("ÉCOLEécole1", &[]).is_empty());
+        assert!(p.check("ΣΟΦΙΑσοφια1", &[]).is_empty());
+        assert_eq!(p.check("ÉCOLEECOLE1", &[]), [Rule::MixedCase]);
+        assert_eq!(p.check("écoleecole1", &[]), [Rule::MixedCase]);
+        // Caseless scripts don't count as either
+        assert_eq!(p.check("パスワード12345", &[]), [Rule::MixedCase]);
+    }
+
+    #[test]
+    fn digits() {
+        let p = policy(None);
+        assert_eq!(p.check("Abcdefgh", &[]), [Rule::Digit]);
+        assert!(p.check("Abcdefg0", &[]).is_empty());
+    }
+
+    #[test]
+    fn personal_info() {
+        let p = policy(None);
+        let personal = ["Al", "Smith", "asmith"];
+        assert!(p.check("Unrelated99", &personal).is_empty());
+        // Any non-empty name counts, as in the form, ignoring case
+        assert_eq!(p.check("Calibrate99", &personal), [Rule::PersonalInfo]);
+        assert_eq!(p.check("SMITHsmith9", &personal), [Rule::PersonalInfo]);
+        assert_eq!(p.check("xxASMITH9x", &personal), [Rule::PersonalInfo]);
+        assert_eq!(p.check("ÉmileBis99", &["émile"]), [Rule::PersonalInfo]);
+        // Blank names and padding are ignored
+        assert!(p.check("Unrelated99", &["", "  "]).is_empty());
+        assert_eq!(p.check("Smithery99", &[" smith "]), [Rule::PersonalInfo]);
+
+        let lenient = PasswordPolicy::new(&PasswordPolicyConfig {
+            forbid_personal_info: false,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(lenient.check("Smithery99", &personal).is_empty());
+    }
+
+    #[test]
+    fn breached_file() {
+        let path = temp_path("list.txt");
+        std::fs::write(
+            &path,
+            format!(
+                "{}:3\n\n{}:0\nnot a hash\n{}\n",
+                PASSW0RD,
+                TR0UB4DOR,
+                PASSW0RD.to_lowercase()
+            ),
+        )
+        .unwrap();
+        let p = policy(Some(path.to_string_lossy().into_owned()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(p.info().check_breached);
+        assert_eq!(p.check("Passw0rd", &[]), [Rule::Breached]);
+        // Count 0 is padding
+        assert!(p.check("Tr0ub4dor", &[]).is_empty());
+        assert!(p.check("Unbreached9", &[]).is_empty());
+
+        assert!(PasswordPolicy::new(&PasswordPolicyConfig {
+            breached_list: Some(temp_path("missing").to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn breached_ranges() {
+        let dir = temp_path("ranges");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (prefix, suffix) = PASSW0RD.split_at(PREFIX_LEN);
+        std::fs::write(
+            dir.join(format!("{}.txt", prefix)),
+            format!("{}:2\n{}:3\n", "0".repeat(35), suffix),
+        )
+        .unwrap();
+        let (prefix, suffix) = TR0UB4DOR.split_at(PREFIX_LEN);
+        std::fs::write(
+            dir.join(format!("{}.txt", prefix)),
+            format!("{}:0\n", suffix),
+        )
+        .unwrap();
+        let p = policy(Some(dir.to_string_lossy().into_owned()));
+        assert!(p.info().check_breached);
+        assert_eq!(p.check("Passw0rd", &[]), [Rule::Breached]);
+        assert!(p.check("Tr0ub4dor", &[]).is_empty());
+        // No range file at all
+        assert!(p.check("Unbreached9", &[]).is_empty());
+        // Without the extension, and with whole lower case hashes
+        let (prefix, _) = UNBREACHED.split_at(PREFIX_LEN);
+        std::fs::write(
+            dir.join(prefix),
+            format!("{}:1\n", UNBREACHED.to_lowercase()),
+        )
+        .unwrap();
+        assert_eq!(p.check("Unbreached9", &[]), [Rule::Breached]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn codes() {
+        let p = policy(None);
+        let failed = p.check("abc", &["abc"]);
+        assert_eq!(
+            failed.iter().map(|r| r.code()).collect::<Vec<_>>(),
+            ["too_short", "mixed_case", "digit", "personal_info"]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Client addresses behind reverse proxies. X-Forwarded-For and Forwarded
+// are only believed when the connection comes from a trusted proxy, and
+// are then walked right to left until the first untrusted hop.
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+// "10.0.0.0/8", "fd00::/8", or a single address
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{} is not an IP address or CIDR range", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{} has an invalid prefix length", s))?,
+            None => max,
+        };
+        // IPv4-mapped ranges ("::ffff:10.0.0.0/104") are matched as IPv4,
+        // so their prefix counts from the start of the IPv4 part
+        let canonical = addr.to_canonical();
+        let prefix = if addr.is_ipv6() && canonical.is_ipv4() {
+            prefix
+                .checked_sub(96)
+                .ok_or_else(|| format!("{} is wider than the IPv4-mapped range", s))?
+        } else {
+            prefix
+        };
+        if canonical.is_ipv4() && prefix > 32 {
+            return Err(format!("{} has an invalid prefix length", s));
+        }
+        Ok(Cidr {
+            addr: canonical,
+            prefix,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Managed state built from settings.trusted_proxies
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn new(cidrs: &[String]) -> Result<Self, String> {
+        let cidrs = cidrs
+            .iter()
+            .map(|c| Cidr::parse(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies(cidrs))
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|c| c.contains(ip))
+    }
+}
+
+// A node from Forwarded's for= or X-Forwarded-For: "192.0.2.1",
+// "192.0.2.1:8080", "\"[2001:db8::1]:8080\"". "unknown" and obfuscated
+// identifiers give None.
+fn parse_node(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Some(v6) = s.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    s.parse()
+        .ok()
+        .or_else(|| s.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok()))
+}
+
+// Hops as added by each proxy, nearest last. Forwarded (RFC 7239) wins
+// over X-Forwarded-For when both are present.
+fn forwarded_chain(req: &Request<'_>) -> Vec<Option<IpAddr>> {
+    let headers = req.headers();
+    let forwarded: Vec<_> = headers
+        .get("Forwarded")
+        .flat_map(|h| h.split(','))
+        .filter_map(|elem| {
+            elem.split(';').find_map(|pair| {
+                let (k, v) = pair.split_once('=')?;
+                k.trim().eq_ignore_ascii_case("for").then(|| parse_node(v))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get("X-Forwarded-For")
+        .flat_map(|h| h.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+fn resolve(req: &Request<'_>) -> Option<IpAddr> {
+    // Rocket's own ip_header is disabled, so this is the peer address
+    let peer = req.client_ip()?;
+    let Some(proxies) = req.rocket().state::<TrustedProxies>() else {
+        return Some(peer);
+    };
+    let mut client = peer;
+    if !proxies.trusts(client) {
+        return Some(client);
+    }
+    for hop in forwarded_chain(req).into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !proxies.trusts(ip) {
+                    break;
+                }
+            }
+            // Can't see past an unknown hop; the last known one is the client
+            None => break,
+        }
+    }
+    Some(client.to_canonical())
+}
+
+// The client's address, for logs and rate limits; never fails, but is
+// None for connections without a peer address (e.g. local test clients)
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(req: &Request<'_>) -> ClientIp {
+        *req.local_cache(|| ClientIp(resolve(req)))
+    }
+}
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(ip) => write!(f, "{}", ip),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp::of(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        // Mapped client addresses, as dual-stack listeners report them
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("fd00::1")));
+
+        let single = Cidr::parse("192.0.2.1").unwrap();
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+
+        let all = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(all.contains(ip("203.0.113.7")));
+        assert!(!all.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let cidr = Cidr::parse("fd00::/8").unwrap();
+        assert!(cidr.contains(ip("fd12:3456::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+        assert!(!cidr.contains(ip("10.0.0.1")));
+
+        let single = Cidr::parse("2001:db8::1").unwrap();
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn mapped_ranges() {
+        let cidr = Cidr::parse("::ffff:10.0.0.0/104").unwrap();
+        assert_eq!(cidr.addr, ip("10.0.0.0"));
+        assert_eq!(cidr.prefix, 8);
+        assert!(cidr.contains(ip("10.200.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.200.0.1")));
+        assert!(!cidr.contains(ip("192.168.0.1")));
+
+        let single = Cidr::parse("::ffff:192.0.2.1").unwrap();
+        assert_eq!(single.prefix, 32);
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+
+        assert!(Cidr::parse("::ffff:0.0.0.0/95").is_err());
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for s in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/",
+            "example.org",
+        ] {
+            assert!(Cidr::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node(" 192.0.2.1:8080 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:8080\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("\"_gazonk:8080\""), None);
+        assert_eq!(parse_node(""), None);
+    }
+}
@@ -0,0 +1,124 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// In-memory, per-process rate limiting. Counts reset when the server
+// restarts, and aren't shared between instances; good enough to slow
+// down abuse of endpoints that send mail or check secrets.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+// Above this many keys, expired windows are swept out on insert
+const SWEEP_THRESHOLD: usize = 4096;
+// Beyond it, the oldest window is dropped to make room for a new key, so
+// rotating addresses can't grow the map without bound
+const MAX_KEYS: usize = 65536;
+
+// Allows `limit` hits per key within a fixed window
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    max_keys: usize,
+    hits: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            max_keys: MAX_KEYS,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Records a hit for `key`; false if it is over the limit
+    pub fn check(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        if hits.len() > SWEEP_THRESHOLD {
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        if hits.len() >= self.max_keys && !hits.contains_key(key) {
+            let oldest = hits
+                .iter()
+                .min_by_key(|(_, (start, _))| *start)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                debug!("rate limiter full; forgetting {}", oldest);
+                hits.remove(&oldest);
+            }
+        }
+        let entry = hits.entry(key.to_owned()).or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        if entry.1 > self.limit {
+            debug!("rate limit hit for {}", key);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+// Managed state for the password reset endpoints
+pub struct PasswordResetLimits {
+    pub per_email: RateLimiter,
+    pub per_ip: RateLimiter,
+}
+
+// Managed state for starting passkey logins, each of which holds a
+// challenge in memory until it finishes or times out
+pub struct PasskeyLoginLimits {
+    pub per_ip: RateLimiter,
+}
+
+// Managed state for checking second factors. Separate buckets, so someone
+// who knows the password can't lock the owner out of their settings.
+pub struct SecondFactorLimits {
+    // Logging in, keyed by user and client address
+    pub login: RateLimiter,
+    // Confirming and disabling from a full session, keyed by user
+    pub account: RateLimiter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(3600));
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        assert!(limiter.check("b"));
+    }
+
+    #[test]
+    fn window_resets() {
+        let limiter = RateLimiter::new(1, Duration::ZERO);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+    }
+
+    #[test]
+    fn bounded_keys() {
+        let limiter = RateLimiter {
+            max_keys: 3,
+            ..RateLimiter::new(1, Duration::from_secs(3600))
+        };
+        for key in ["a", "b", "c", "d", "e"] {
+            assert!(limiter.check(key));
+        }
+        assert_eq!(limiter.hits.lock().unwrap().len(), 3);
+        // The newest keys are still limited; the oldest were forgotten
+        assert!(!limiter.check("e"));
+        assert!(limiter.check("a"));
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::consts::*;
+
+use std::time::{Duration, Instant};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Data, Request, Response,
+};
+use uuid::Uuid;
+
+// Identifies a single request in logs and error responses
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// IDs from upstream proxies end up in logs, so only accept tame ones
+fn acceptable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+impl RequestId {
+    // Taken from X-Request-Id if the client or a proxy sent a usable one,
+    // otherwise generated; assigned lazily, then fixed for the request
+    pub fn of<'a>(req: &'a Request<'_>) -> &'a RequestId {
+        req.local_cache(|| match req.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) if acceptable(id) => RequestId(id.to_owned()),
+            _ => RequestId(Uuid::new_v4().simple().to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// When the request arrived, for latencies in the access log and metrics
+pub struct RequestStart(Instant);
+
+impl RequestStart {
+    pub fn of<'a>(req: &'a Request<'_>) -> &'a RequestStart {
+        req.local_cache(|| RequestStart(Instant::now()))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+// Records each request's start and echoes its ID in X-Request-Id.
+// Attach before the fairings that read RequestStart.
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestStart::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(req).0.clone()));
+    }
+}
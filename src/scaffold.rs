@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// `fastrequest migrate new`: writes an empty migration into src/migrator,
+// which build.rs then picks up
+
+use std::error::Error;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+const MAX_NAME_LEN: usize = 64;
+
+// Existing migrations as (stem, name) pairs, e.g.
+// ("m20240629_000001_create_table_user", "create_table_user"), sorted
+fn existing(dir: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let file = entry?.file_name().to_string_lossy().into_owned();
+        let Some(stem) = file.strip_suffix(".rs") else {
+            continue;
+        };
+        if !stem.starts_with('m') || stem == "mod" {
+            continue;
+        }
+        // m + YYYYMMDD + _ + 6 digits + _ + name; ASCII only, so the
+        // slices below can't split a character
+        let name = stem.get(17..).filter(|_| {
+            stem.is_ascii()
+                && stem.len() > 17
+                && stem[1..9].bytes().all(|b| b.is_ascii_digit())
+                && &stem[9..10] == "_"
+                && stem[10..16].bytes().all(|b| b.is_ascii_digit())
+                && &stem[16..17] == "_"
+        });
+        match name {
+            Some(name) => found.push((stem.to_owned(), name.to_owned())),
+            None => warn!(
+                "{} does not look like a migration; build.rs will still load it",
+                file
+            ),
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    let ok = name.len() <= MAX_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !name.ends_with('_')
+        && !name.contains("__");
+    if ok {
+        Ok(())
+    } else {
+        Err(format!(
+            "migration names must be snake_case (a-z, 0-9, '_'), start with a letter \
+             and be at most {} characters, e.g. create_table_request",
+            MAX_NAME_LEN
+        ))
+    }
+}
+
+// What a migration does, from its name's prefix; picks the skeleton
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    CreateTable,
+    AlterTable,
+    DropTable,
+    CreateIndex,
+    Other,
+}
+
+const PREFIXES: [(&str, Kind); 4] = [
+    ("create_table_", Kind::CreateTable),
+    ("alter_table_", Kind::AlterTable),
+    ("drop_table_", Kind::DropTable),
+    ("create_index_", Kind::CreateIndex),
+];
+
+// "create_table_agency_contact" -> (CreateTable, "agency_contact")
+fn split_kind(name: &str) -> (Kind, &str) {
+    PREFIXES
+        .iter()
+        .find_map(|(p, kind)| name.strip_prefix(p).map(|rest| (*kind, rest)))
+        .unwrap_or((Kind::Other, name))
+}
+
+// "agency_contact" -> "AgencyContact", for the Iden enum
+fn iden_name(table: &str) -> String {
+    table
+        .split('_')
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            let mut c = w.chars();
+            c.next()
+                .map(|f| f.to_ascii_uppercase().to_string() + c.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+// The up and down bodies and the Iden enum's variants for `kind`
+fn skeleton(kind: Kind, stem: &str, table: &str, iden: &str) -> (String, String, &'static str) {
+    match kind {
+        Kind::CreateTable => (
+            format!(
+                r#"manager
+            .create_table(
+                Table::create()
+                    .table({iden}::Table)
+                    .col(ColumnDef::new({iden}::Id).uuid().not_null().primary_key())
+                    .to_owned(),
+            )
+            .await"#
+            ),
+            format!(
+                r#"manager
+            .drop_table(Table::drop().table({iden}::Table).to_owned())
+            .await"#
+            ),
+            "Table,\n    Id,",
+        ),
+        // The enum only names the new column; point .table() at the
+        // altered table's Iden from its create_table migration
+        Kind::AlterTable => (
+            format!(
+                r#"manager
+            .alter_table(
+                Table::alter()
+                    .table(Alias::new("{table}"))
+                    .add_column(ColumnDef::new({iden}::Column).string().null())
+                    .to_owned(),
+            )
+            .await"#
+            ),
+            format!(
+                r#"manager
+            .alter_table(
+                Table::alter()
+                    .table(Alias::new("{table}"))
+                    .drop_column({iden}::Column)
+                    .to_owned(),
+            )
+            .await"#
+            ),
+            "Column,",
+        ),
+        Kind::DropTable => (
+            format!(
+                r#"manager
+            .drop_table(Table::drop().table({iden}::Table).to_owned())
+            .await"#
+            ),
+            format!(
+                r#"let _ = manager;
+        Err(DbErr::Migration(
+            "{stem} cannot be reverted".to_owned(),
+        ))"#
+            ),
+            "Table,",
+        ),
+        Kind::CreateIndex => (
+            format!(
+                r#"manager
+            .create_index(
+                Index::create()
+                    .name("IDX_{table}")
+                    .table({iden}::Table)
+                    .col({iden}::Column)
+                    .to_owned(),
+            )
+            .await"#
+            ),
+            format!(
+                r#"manager
+            .drop_index(
+                Index::drop()
+                    .name("IDX_{table}")
+                    .table({iden}::Table)
+                    .to_owned(),
+            )
+            .await"#
+            ),
+            "Table,\n    Column,",
+        ),
+        Kind::Other => {
+            let noop = "let _ = manager;\n        Ok(())".to_owned();
+            (noop.clone(), noop, "")
+        }
+    }
+}
+
+fn template(stem: &str, name: &str) -> String {
+    let (kind, table) = split_kind(name);
+    let iden = iden_name(table);
+    let (up, down, variants) = skeleton(kind, stem, table, &iden);
+    let idens = if variants.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\n#[derive(Iden)]\npub enum {} {{\n    {}\n}}\n",
+            iden, variants
+        )
+    };
+    format!(
+        r#"// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {{
+    fn name(&self) -> &str {{
+        "m_{migration_name}"
+    }}
+}}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {{
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {{
+        {up}
+    }}
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {{
+        {down}
+    }}
+}}
+{idens}"#,
+        // MigrationName strings have an underscore after the 'm'
+        migration_name = &stem[1..],
+    )
+}
+
+// Writes m<YYYYMMDD>_<HHMMSS>_<name>.rs into `dir` and returns its path.
+// Migrations are applied in file name order, so when the latest one is
+// from the same day and doesn't sort before now (the six digits may be a
+// sequence number, as in this repo's own), the next number after it is
+// taken instead. Refuses names already used, and dates before the latest.
+pub fn new_migration(dir: &Path, name: &str, now: DateTime<Utc>) -> Result<String, Box<dyn Error>> {
+    validate_name(name)?;
+    let (kind, table) = split_kind(name);
+    if kind != Kind::Other && !iden_name(table).starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(format!("cannot derive a table name from {}", name).into());
+    }
+    let existing = existing(dir)?;
+    if let Some((stem, _)) = existing.iter().find(|(_, n)| n == name) {
+        return Err(format!("a migration named {} already exists: {}", name, stem).into());
+    }
+    let mut stamp = now.format("%Y%m%d_%H%M%S").to_string();
+    if let Some((latest, _)) = existing.last() {
+        let (date, time) = (&latest[1..9], &latest[10..16]);
+        if stamp.as_str() <= &latest[1..16] {
+            // Digits only, checked by `existing`
+            let next = time.parse::<u32>()? + 1;
+            if stamp[..8] != *date || next > 999_999 {
+                return Err(format!(
+                    "m{}_{} would not sort after the latest migration {}; \
+                     is the clock wrong?",
+                    stamp, name, latest
+                )
+                .into());
+            }
+            stamp = format!("{}_{:06}", date, next);
+        }
+    }
+    let stem = format!("m{}_{}", stamp, name);
+    let path = dir.join(format!("{}.rs", stem));
+    // create_new: never clobber, even if something raced us
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, template(&stem, name).as_bytes()))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use chrono::TimeZone;
+
+    // An empty directory under the system temp directory for one test
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("frq-scaffold-test-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn touch(dir: &Path, file: &str) {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn names() {
+        for ok in ["create_table_request", "alter_table_user_x2", "backfill"] {
+            assert!(validate_name(ok).is_ok(), "{}", ok);
+        }
+        let too_long = "a".repeat(MAX_NAME_LEN + 1);
+        for bad in [
+            "",
+            "CreateTable",
+            "2fa",
+            "create-table",
+            "create__table",
+            "create_table_",
+            "tablé",
+            too_long.as_str(),
+        ] {
+            assert!(validate_name(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn lists_sorted_and_skips_strays() {
+        let dir = temp_dir("existing");
+        touch(&dir, "m20240708_000001_create_table_cookie.rs");
+        touch(&dir, "m20240629_000001_create_table_user.rs");
+        touch(&dir, "mod.rs");
+        touch(&dir, "README.md");
+        // Non-ASCII names used to panic when sliced
+        touch(&dir, "m2024é0629_000001_oops.rs");
+        touch(&dir, "mé.rs");
+        touch(&dir, "m20240629.rs");
+        let found = existing(&dir).unwrap();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "m20240629_000001_create_table_user".to_owned(),
+                    "create_table_user".to_owned()
+                ),
+                (
+                    "m20240708_000001_create_table_cookie".to_owned(),
+                    "create_table_cookie".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_after_the_latest() {
+        let dir = temp_dir("after");
+        touch(&dir, "m20240629_000001_create_table_user.rs");
+        let path = new_migration(&dir, "create_table_agency", at(2024, 7, 1, 12, 30, 5)).unwrap();
+        assert!(path.ends_with("m20240701_123005_create_table_agency.rs"));
+        let src = std::fs::read_to_string(&path).unwrap();
+        assert!(src.contains("\"m_20240701_123005_create_table_agency\""));
+        assert!(src.contains("pub enum Agency {"));
+    }
+
+    #[test]
+    fn refuses_out_of_order() {
+        let dir = temp_dir("order");
+        touch(&dir, "m20240708_000001_create_table_cookie.rs");
+        // A day before the latest
+        assert!(new_migration(&dir, "create_table_agency", at(2024, 7, 1, 12, 0, 0)).is_err());
+        // Nothing comes after 999999 on the same day
+        touch(&dir, "m20240709_999999_create_table_agency.rs");
+        assert!(new_migration(&dir, "create_table_request", at(2024, 7, 9, 0, 0, 0)).is_err());
+        assert_eq!(existing(&dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn same_day_takes_the_next_number() {
+        let dir = temp_dir("same-day");
+        touch(&dir, "m20261018_000008_create_table_webauthn_credential.rs");
+        // Before 00:00:09, the sequence numbers would otherwise collide
+        let path = new_migration(&dir, "create_table_agency", at(2026, 10, 18, 0, 0, 5)).unwrap();
+        assert!(path.ends_with("m20261018_000009_create_table_agency.rs"));
+        let src = std::fs::read_to_string(&path).unwrap();
+        assert!(src.contains("\"m_20261018_000009_create_table_agency\""));
+        // Later in the day, the clock wins again
+        let path = new_migration(&dir, "create_table_request", at(2026, 10, 18, 9, 30, 0)).unwrap();
+        assert!(path.ends_with("m20261018_093000_create_table_request.rs"));
+        // And the same second as the latest moves on by one
+        let path = new_migration(&dir, "create_table_note", at(2026, 10, 18, 9, 30, 0)).unwrap();
+        assert!(path.ends_with("m20261018_093001_create_table_note.rs"));
+    }
+
+    #[test]
+    fn refuses_collisions() {
+        let dir = temp_dir("collision");
+        touch(&dir, "m20240629_000001_create_table_user.rs");
+        // The name is taken, whatever the timestamp
+        assert!(new_migration(&dir, "create_table_user", at(2025, 1, 1, 0, 0, 0)).is_err());
+        // Nor twice at different times
+        new_migration(&dir, "create_table_agency", at(2025, 1, 1, 0, 0, 0)).unwrap();
+        assert!(new_migration(&dir, "create_table_agency", at(2025, 1, 2, 0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn refuses_invalid_names() {
+        let dir = temp_dir("invalid");
+        assert!(new_migration(&dir, "Create Table", at(2025, 1, 1, 0, 0, 0)).is_err());
+        assert!(new_migration(&dir, "create_table_2fa", at(2025, 1, 1, 0, 0, 0)).is_err());
+        assert!(existing(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn skeleton_follows_prefix() {
+        let stem = "m20250101_000000_";
+        let cases = [
+            ("create_table_agency", "create_table("),
+            ("alter_table_user_phone", "alter_table("),
+            ("drop_table_agency", "drop_table("),
+            ("create_index_agency_name", "create_index("),
+            ("backfill_usernames", "Ok(())"),
+        ];
+        for (name, expected) in cases {
+            let src = template(&[stem, name].concat(), name);
+            assert!(src.contains(expected), "{}:\n{}", name, src);
+        }
+        let alter = template(
+            "m20250101_000000_alter_table_user_phone",
+            "alter_table_user_phone",
+        );
+        assert!(!alter.contains("create_table"));
+        assert!(alter.contains("drop_column(UserPhone::Column)"));
+    }
+}
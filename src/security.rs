@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::config::HeadersConfig;
+
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    shield::{Hsts, Permission, Shield},
+    time::Duration,
+    Request, Response,
+};
+use sha2::{Digest, Sha256};
+
+// Baseline policy; [security.headers.content_security_policy] is merged over it
+fn default_csp() -> BTreeMap<String, Vec<String>> {
+    [
+        ("default-src", &["'self'"][..]),
+        ("script-src", &["'self'"]),
+        // flowbite/popper position elements with inline styles
+        ("style-src", &["'self'", "'unsafe-inline'"]),
+        ("img-src", &["'self'", "data:"]),
+        ("font-src", &["'self'"]),
+        ("connect-src", &["'self'"]),
+        ("object-src", &["'none'"]),
+        ("base-uri", &["'self'"]),
+        ("form-action", &["'self'"]),
+        ("frame-ancestors", &["'none'"]),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v.iter().map(|s| s.to_string()).collect()))
+    .collect()
+}
+
+// 'sha256-...' sources for every inline <script> in the built index.html,
+// so script-src never needs 'unsafe-inline'
+fn inline_script_hashes(html: &str) -> Vec<String> {
+    let mut hashes = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("<script") {
+        rest = &rest[start..];
+        let Some(tag_end) = rest.find('>') else { break };
+        let tag = &rest[..tag_end];
+        let body = &rest[tag_end + 1..];
+        let Some(close) = body.find("</script>") else {
+            break;
+        };
+        if !tag.contains(" src=") && !body[..close].trim().is_empty() {
+            let digest = Sha256::digest(body[..close].as_bytes());
+            hashes.push(format!("'sha256-{}'", STANDARD.encode(digest)));
+        }
+        rest = &body[close..];
+    }
+    hashes
+}
+
+fn build_csp(conf: &HeadersConfig, index_html: &str) -> String {
+    let mut directives = default_csp();
+    for (k, v) in &conf.content_security_policy {
+        directives.insert(k.clone(), v.clone());
+    }
+    let hashes = inline_script_hashes(index_html);
+    debug!("CSP: {} inline script hashes from index.html", hashes.len());
+    if let Some(script_src) = directives.get_mut("script-src") {
+        script_src.extend(hashes);
+    }
+    directives
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!("{} {}", k, v.join(" ")))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// Host part of a URL such as "https://[::1]:4433/path"
+pub fn url_host(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, r)| r);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or("");
+    if let Some(v6) = authority.strip_prefix('[') {
+        v6.split(']').next().unwrap_or("")
+    } else {
+        authority.split(':').next().unwrap_or("")
+    }
+}
+
+fn is_localhost(url: &str) -> bool {
+    let host = url_host(url).to_ascii_lowercase();
+    host == "localhost"
+        || host.ends_with(".localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+// Whether to send Strict-Transport-Security; unless configured, only when
+// the site is actually served over HTTPS (TLS here, or an https://
+// settings.url behind a proxy) and settings.url isn't a loopback address
+pub fn hsts_enabled(conf: &HeadersConfig, url: Option<&str>, tls: bool) -> bool {
+    conf.hsts.enabled.unwrap_or_else(|| {
+        let https = tls
+            || url.is_some_and(|u| {
+                u.get(..8)
+                    .is_some_and(|s| s.eq_ignore_ascii_case("https://"))
+            });
+        https && !url.is_some_and(is_localhost)
+    })
+}
+
+// Rocket's Shield, minus the policies SecurityHeaders sets from config
+pub fn shield(conf: &HeadersConfig, hsts: bool) -> Shield {
+    let shield = Shield::default().disable::<Permission>();
+    if !hsts {
+        return shield;
+    }
+    let age = Duration::days(conf.hsts.max_age_days);
+    shield.enable(if conf.hsts.preload {
+        Hsts::Preload(age)
+    } else if conf.hsts.include_subdomains {
+        Hsts::IncludeSubDomains(age)
+    } else {
+        Hsts::Enable(age)
+    })
+}
+
+// Sets CSP, Referrer-Policy and Permissions-Policy.
+// Must be attached after `shield()`: Shield forces HSTS on for TLS release
+// builds, so with HSTS disabled its header is removed here.
+pub struct SecurityHeaders {
+    csp: String,
+    csp_header: &'static str,
+    referrer_policy: String,
+    permissions_policy: String,
+    hsts: bool,
+}
+
+impl SecurityHeaders {
+    pub fn new(conf: &HeadersConfig, index_html: &str, hsts: bool) -> Self {
+        SecurityHeaders {
+            csp: build_csp(conf, index_html),
+            csp_header: if conf.csp_report_only {
+                "Content-Security-Policy-Report-Only"
+            } else {
+                "Content-Security-Policy"
+            },
+            referrer_policy: conf.referrer_policy.clone(),
+            permissions_policy: conf.permissions_policy.clone(),
+            hsts,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Liftoff | Kind::Response,
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &rocket::Rocket<rocket::Orbit>) {
+        info!("Content-Security-Policy: {}", self.csp);
+        if !self.hsts {
+            info!("HSTS is disabled");
+        }
+    }
+
+    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !self.csp.is_empty() {
+            response.set_header(Header::new(self.csp_header, self.csp.clone()));
+        }
+        if !self.referrer_policy.is_empty() {
+            response.set_header(Header::new("Referrer-Policy", self.referrer_policy.clone()));
+        }
+        if !self.permissions_policy.is_empty() {
+            response.set_header(Header::new(
+                "Permissions-Policy",
+                self.permissions_policy.clone(),
+            ));
+        }
+        if !self.hsts {
+            response.remove_header("Strict-Transport-Security");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Stateless, expiring tokens for links sent by email. A token is
+//   base64url(user id || expiry) "." base64url(HMAC-SHA256)
+// where the MAC also covers a purpose, so tokens can't be reused for
+// another flow, and a caller-chosen binding (e.g. the email address being
+// verified), so tokens die once the thing they were issued for changes.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    // Wrong signature, purpose or binding
+    Invalid,
+    Expired,
+}
+
+// A token that parsed, but whose signature hasn't been checked yet;
+// `user_id` is only good for looking up the binding
+pub struct Unverified {
+    pub user_id: Uuid,
+    expires: i64,
+    mac: Vec<u8>,
+}
+
+// Managed state; keyed from the session secret
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        // Derived, so the cookie key itself never signs anything
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(b"fastrequest tokens v1");
+        TokenSigner {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, purpose: &str, payload: &[u8], binding: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        for part in [purpose.as_bytes(), payload, binding.as_bytes()] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac
+    }
+
+    pub fn sign(&self, purpose: &str, user_id: Uuid, binding: &str, lifetime: Duration) -> String {
+        let expires = (Utc::now() + lifetime).timestamp();
+        let mut payload = user_id.as_bytes().to_vec();
+        payload.extend_from_slice(&expires.to_be_bytes());
+        let tag = self.mac(purpose, &payload, binding).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(tag)
+        )
+    }
+
+    pub fn parse(token: &str) -> Result<Unverified, TokenError> {
+        let (payload, mac) = token.trim().split_once('.').ok_or(TokenError::Malformed)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| TokenError::Malformed)?;
+        let mac = URL_SAFE_NO_PAD
+            .decode(mac)
+            .map_err(|_| TokenError::Malformed)?;
+        if payload.len() != 24 {
+            return Err(TokenError::Malformed);
+        }
+        Ok(Unverified {
+            user_id: Uuid::from_slice(&payload[..16]).map_err(|_| TokenError::Malformed)?,
+            expires: i64::from_be_bytes(payload[16..].try_into().unwrap()),
+            mac,
+        })
+    }
+
+    pub fn verify(
+        &self,
+        purpose: &str,
+        token: &Unverified,
+        binding: &str,
+    ) -> Result<(), TokenError> {
+        let mut payload = token.user_id.as_bytes().to_vec();
+        payload.extend_from_slice(&token.expires.to_be_bytes());
+        // verify_slice compares in constant time
+        self.mac(purpose, &payload, binding)
+            .verify_slice(&token.mac)
+            .map_err(|_| TokenError::Invalid)?;
+        if Utc::now().timestamp() > token.expires {
+            return Err(TokenError::Expired);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Time-based one-time passwords (RFC 6238) as a second login factor.
+// Shared secrets are stored AES-256-GCM encrypted under a key from the
+// secrets, so a database dump alone can't be used to generate codes.
+// Recovery codes are single use and only stored as SHA-256 hashes.
+
+use crate::api::{ApiError, ApiResult};
+use crate::entities::{prelude::*, recovery_code, totp, user};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rocket::http::Status;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DbErr, EntityTrait,
+    QueryFilter, Set, TransactionTrait,
+};
+use serde_derive::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// The parameters every authenticator app supports
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// Steps either side of now that are accepted, for clock drift
+const SKEW: i64 = 1;
+const SECRET_LEN: usize = 20;
+const NONCE_LEN: usize = 12;
+const ISSUER: &str = "FastRequest";
+const RECOVERY_CODES: usize = 10;
+// In groups of five; 50 bits each
+const RECOVERY_CODE_LEN: usize = 10;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 base32 without padding, as authenticator apps expect
+fn base32(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let (mut buf, mut bits) = (0u32, 0);
+    for &b in bytes {
+        buf = (buf << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buf >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buf << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+// RFC 4226 HOTP with HMAC-SHA1
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+fn current_step() -> i64 {
+    chrono::Utc::now().timestamp() / PERIOD
+}
+
+// The time step `code` is valid for, if any, within the allowed skew
+fn matching_step(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    let code: u32 = code.parse().ok()?;
+    // Every candidate is computed, so timing doesn't tell which one matched
+    let mut found = None;
+    for step in now - SKEW..=now + SKEW {
+        if hotp(secret, step as u64) == code && found.is_none() {
+            found = Some(step);
+        }
+    }
+    found
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+// otpauth:// URI for QR codes, per the Key Uri Format authenticator apps use
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> String {
+    let issuer = utf8_percent_encode(ISSUER, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        utf8_percent_encode(account, NON_ALPHANUMERIC),
+        base32(secret),
+        issuer,
+        DIGITS,
+        PERIOD
+    )
+}
+
+// Managed state. None if no totp.encryption_key secret is configured, in
+// which case enrolling and verifying codes are unavailable.
+pub struct TotpCipher(Option<Aes256Gcm>);
+
+impl TotpCipher {
+    // Any string works as the key; it is stretched to 256 bits with SHA-256,
+    // so it should be long and random, e.g. `openssl rand -base64 32`
+    pub fn new(key: Option<&str>) -> Self {
+        if key.is_none() {
+            warn!("no totp.encryption_key secret; two-factor authentication is unavailable");
+        }
+        TotpCipher(key.map(|k| {
+            Aes256Gcm::new_from_slice(&Sha256::digest(k.as_bytes()))
+                .expect("SHA-256 output is a valid AES-256 key")
+        }))
+    }
+
+    fn cipher(&self) -> ApiResult<&Aes256Gcm> {
+        self.0.as_ref().ok_or_else(|| {
+            ApiError::new(
+                Status::ServiceUnavailable,
+                "totp_unavailable",
+                "two-factor authentication is not configured on this server",
+            )
+        })
+    }
+
+    // The user id is authenticated along with the secret, so rows can't
+    // be swapped between users
+    fn encrypt(&self, user_id: Uuid, secret: &[u8]) -> ApiResult<(Vec<u8>, Vec<u8>)> {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: secret,
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                error!("failed to encrypt TOTP secret of {}", user_id);
+                ApiError::internal()
+            })?;
+        Ok((ciphertext, nonce))
+    }
+
+    fn decrypt(&self, row: &totp::Model) -> ApiResult<Vec<u8>> {
+        let cipher = self.cipher()?;
+        if row.nonce.len() != NONCE_LEN {
+            error!("TOTP secret of {} has a malformed nonce", row.user_id);
+            return Err(ApiError::internal());
+        }
+        cipher
+            .decrypt(
+                Nonce::from_slice(&row.nonce),
+                Payload {
+                    msg: &row.secret,
+                    aad: row.user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                error!(
+                    "failed to decrypt TOTP secret of {}; was totp.encryption_key changed?",
+                    row.user_id
+                );
+                ApiError::internal()
+            })
+    }
+}
+
+fn bad_code() -> ApiError {
+    ApiError::bad_request("invalid_code", "the code is incorrect or was already used")
+        .on_field("code")
+}
+
+// Lower case without separators, so "ABCDE-FGHIJ" and "abcdefghij" match
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    Sha256::digest(normalize_recovery_code(code).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn random_recovery_code() -> String {
+    let mut bytes = [0u8; RECOVERY_CODE_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    let chars: String = bytes
+        .iter()
+        .map(|b| BASE32[(b & 31) as usize].to_ascii_lowercase() as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+// Whether logging in as the user needs a second factor
+pub async fn is_enabled<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<bool, DbErr> {
+    Ok(Totp::find_by_id(user_id)
+        .one(db)
+        .await?
+        .is_some_and(|t| t.enabled))
+}
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    // base32, for typing into an app by hand
+    pub secret: String,
+    // otpauth:// URI, for showing as a QR code
+    pub uri: String,
+}
+
+// Starts (or restarts) enrolment with a fresh secret. It only takes effect
+// once `confirm` has seen a code generated from it.
+pub async fn enroll<C: ConnectionTrait>(
+    db: &C,
+    cipher: &TotpCipher,
+    user: &user::Model,
+) -> ApiResult<Enrollment> {
+    if is_enabled(db, user.id).await? {
+        return Err(ApiError::conflict(
+            "totp_enabled",
+            "two-factor authentication is already enabled",
+        ));
+    }
+    let mut secret = vec![0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    let (ciphertext, nonce) = cipher.encrypt(user.id, &secret)?;
+    Totp::delete_by_id(user.id).exec(db).await?;
+    totp::ActiveModel {
+        user_id: Set(user.id),
+        secret: Set(ciphertext),
+        nonce: Set(nonce),
+        enabled: Set(false),
+        last_step: Set(None),
+        created_datetime: Set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(db)
+    .await?;
+    debug!("started TOTP enrolment for {}", user.id);
+    Ok(Enrollment {
+        secret: base32(&secret),
+        uri: provisioning_uri(&user.username, &secret),
+    })
+}
+
+// Records `step` as used; false if it (or a later one) already was
+async fn spend_step<C: ConnectionTrait>(db: &C, user_id: Uuid, step: i64) -> Result<bool, DbErr> {
+    let res = Totp::update_many()
+        .col_expr(totp::Column::LastStep, Expr::value(step))
+        .filter(totp::Column::UserId.eq(user_id))
+        .filter(
+            Condition::any()
+                .add(totp::Column::LastStep.is_null())
+                .add(totp::Column::LastStep.lt(step)),
+        )
+        .exec(db)
+        .await?;
+    Ok(res.rows_affected == 1)
+}
+
+// Checks a code from the app being enrolled, enables TOTP and returns a
+// new set of recovery codes, replacing any earlier ones
+pub async fn confirm<C: TransactionTrait>(
+    db: &C,
+    cipher: &TotpCipher,
+    user_id: Uuid,
+    code: &str,
+) -> ApiResult<Vec<String>> {
+    let txn = db.begin().await?;
+    let row = match Totp::find_by_id(user_id).one(&txn).await? {
+        Some(row) if !row.enabled => row,
+        Some(_) => {
+            return Err(ApiError::conflict(
+                "totp_enabled",
+                "two-factor authentication is already enabled",
+            ))
+        }
+        None => {
+            return Err(ApiError::bad_request(
+                "not_enrolling",
+                "start enrolment before confirming it",
+            ))
+        }
+    };
+    let secret = cipher.decrypt(&row)?;
+    let code = code.trim();
+    let step = is_totp_code(code)
+        .then(|| matching_step(&secret, code, current_step()))
+        .flatten()
+        .ok_or_else(bad_code)?;
+    if !spend_step(&txn, user_id, step).await? {
+        return Err(bad_code());
+    }
+    let mut model: totp::ActiveModel = row.into();
+    model.enabled = Set(true);
+    model.update(&txn).await?;
+
+    RecoveryCode::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| random_recovery_code())
+        .collect();
+    RecoveryCode::insert_many(codes.iter().map(|c| recovery_code::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user_id),
+        code_hash: Set(hash_recovery_code(c)),
+        used_datetime: Set(None),
+    }))
+    .exec(&txn)
+    .await?;
+    txn.commit().await?;
+    info!("enabled TOTP for {}", user_id);
+    Ok(codes)
+}
+
+// Accepts either a current code from the app or an unused recovery code,
+// spending it either way. Enabled TOTP is required.
+pub async fn check<C: ConnectionTrait>(
+    db: &C,
+    cipher: &TotpCipher,
+    user_id: Uuid,
+    code: &str,
+) -> ApiResult<()> {
+    let row = Totp::find_by_id(user_id)
+        .one(db)
+        .await?
+        .filter(|t| t.enabled)
+        .ok_or_else(|| {
+            ApiError::bad_request("totp_disabled", "two-factor authentication is not enabled")
+        })?;
+    let code = code.trim();
+    if is_totp_code(code) {
+        let secret = cipher.decrypt(&row)?;
+        return match matching_step(&secret, code, current_step()) {
+            Some(step) if spend_step(db, user_id, step).await? => Ok(()),
+            _ => Err(bad_code()),
+        };
+    }
+    // Conditional, so a recovery code can't be spent twice concurrently
+    let spent = RecoveryCode::update_many()
+        .col_expr(
+            recovery_code::Column::UsedDatetime,
+            Expr::value(chrono::Utc::now().naive_utc()),
+        )
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .filter(recovery_code::Column::CodeHash.eq(hash_recovery_code(code)))
+        .filter(recovery_code::Column::UsedDatetime.is_null())
+        .exec(db)
+        .await?;
+    if spent.rows_affected == 0 {
+        return Err(bad_code());
+    }
+    info!("user {} used a recovery code", user_id);
+    Ok(())
+}
+
+// Removes the authenticator and recovery codes; false if there were none
+pub async fn disable<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<bool, DbErr> {
+    let res = Totp::delete_by_id(user_id).exec(db).await?;
+    RecoveryCode::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    if res.rows_affected > 0 {
+        info!("disabled TOTP for {}", user_id);
+    }
+    Ok(res.rows_affected > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The key of the RFC 4226 and RFC 6238 (SHA-1) test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    // RFC 4226 Appendix D, counters 0 to 9
+    const HOTP: [u32; 10] = [
+        755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+
+    #[test]
+    fn rfc4226_vectors() {
+        for (counter, expected) in HOTP.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *expected, "{}", counter);
+        }
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        // Appendix B lists 8 digits; these are their last 6
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors {
+            let step = time / PERIOD;
+            assert_eq!(format!("{:06}", hotp(RFC_SECRET, step as u64)), code);
+            assert_eq!(matching_step(RFC_SECRET, code, step), Some(step));
+        }
+    }
+
+    #[test]
+    fn base32_vectors() {
+        // RFC 4648 section 10, without padding
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(base32(input.as_bytes()), expected);
+        }
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn skew_window() {
+        let now = 5;
+        let code = |step: usize| HOTP[step].to_string();
+        for step in [4, 5, 6] {
+            assert_eq!(
+                matching_step(RFC_SECRET, &code(step), now),
+                Some(step as i64)
+            );
+        }
+        for step in [3, 7] {
+            assert_eq!(matching_step(RFC_SECRET, &code(step), now), None);
+        }
+        assert_eq!(matching_step(RFC_SECRET, "abcdef", now), None);
+    }
+
+    #[test]
+    fn code_shapes() {
+        assert!(is_totp_code("081804"));
+        assert!(!is_totp_code("81804"));
+        assert!(!is_totp_code("0818041"));
+        assert!(!is_totp_code("abcde-fghij"));
+        assert_eq!(
+            hash_recovery_code("ABCDE-FGHIJ"),
+            hash_recovery_code(" abcdefghij")
+        );
+        let code = random_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+        assert!(!is_totp_code(&code));
+    }
+
+    #[test]
+    fn uri() {
+        assert_eq!(
+            provisioning_uri("jane.doe", RFC_SECRET),
+            "otpauth://totp/FastRequest:jane%2Edoe?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+             &issuer=FastRequest&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
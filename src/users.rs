@@ -0,0 +1,474 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Account operations shared by the API and the command line
+
+use crate::api::{ApiError, ApiResult};
+use crate::auth::{self, HashedPassword};
+use crate::entities::{cookie, password_reset, prelude::*, user};
+use crate::mail::{MailResult, Mailer};
+use crate::password_policy::PasswordPolicy;
+use crate::tokens::{TokenError, TokenSigner};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection,
+    DbErr, EntityTrait, QueryFilter, Set, SqlErr, TransactionTrait,
+};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Field names match the ids used in CreateAccountForm.svelte
+#[derive(Deserialize)]
+pub struct NewAccount {
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    pub email: String,
+    pub organization: Option<String>,
+    pub phone: Option<String>,
+    pub password: String,
+}
+
+const MAX_NAME_LEN: usize = 128;
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+const MAX_EMAIL_LEN: usize = 254;
+
+// Trims and checks every field, returning the normalized account
+pub async fn validate(policy: &PasswordPolicy, mut acc: NewAccount) -> ApiResult<NewAccount> {
+    acc.first_name = acc.first_name.trim().to_owned();
+    acc.last_name = acc.last_name.trim().to_owned();
+    acc.username = acc.username.trim().to_owned();
+    acc.email = acc.email.trim().to_lowercase();
+    acc.organization = acc
+        .organization
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty());
+    acc.phone = acc
+        .phone
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty());
+
+    for (field, v) in [
+        ("first_name", &acc.first_name),
+        ("last_name", &acc.last_name),
+    ] {
+        if v.is_empty() {
+            return Err(ApiError::bad_request("required", "this field is required").on_field(field));
+        }
+        if v.chars().count() > MAX_NAME_LEN {
+            return Err(ApiError::bad_request(
+                "too_long",
+                format!("must be at most {} characters", MAX_NAME_LEN),
+            )
+            .on_field(field));
+        }
+    }
+
+    let ulen = acc.username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&ulen) {
+        return Err(ApiError::bad_request(
+            "invalid_username",
+            format!(
+                "username must be between {} and {} characters",
+                MIN_USERNAME_LEN, MAX_USERNAME_LEN
+            ),
+        )
+        .on_field("username"));
+    }
+    if !acc
+        .username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(ApiError::bad_request(
+            "invalid_username",
+            "username may only contain letters, numbers, '_', '-' and '.'",
+        )
+        .on_field("username"));
+    }
+
+    // Deliberately loose; the mail server is the real validator
+    let email_ok = acc.email.len() <= MAX_EMAIL_LEN
+        && match acc.email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+            }
+            None => false,
+        };
+    if !email_ok {
+        return Err(
+            ApiError::bad_request("invalid_email", "email address is not valid").on_field("email"),
+        );
+    }
+
+    policy
+        .enforce(
+            &acc.password,
+            &[&acc.first_name, &acc.last_name, &acc.username],
+        )
+        .await?;
+    Ok(acc)
+}
+
+// Checks a new password of `user` against the policy
+pub async fn validate_password(
+    policy: &PasswordPolicy,
+    user: &user::Model,
+    password: &str,
+) -> ApiResult<()> {
+    policy
+        .enforce(
+            password,
+            &[&user.first_name, &user.last_name, &user.username],
+        )
+        .await
+}
+
+fn username_taken() -> ApiError {
+    ApiError::conflict("username_taken", "username already taken").on_field("username")
+}
+
+fn email_taken() -> ApiError {
+    ApiError::conflict("email_taken", "an account with this email already exists").on_field("email")
+}
+
+// Argon2 is deliberately slow; keep it off the async workers
+pub async fn hash_password(password: String) -> ApiResult<HashedPassword> {
+    rocket::tokio::task::spawn_blocking(move || auth::hash_password(&password))
+        .await
+        .map_err(|e| {
+            error!("password hashing task failed: {}", e);
+            ApiError::internal()
+        })?
+        .map_err(|e| {
+            error!("failed to hash password: {}", e);
+            ApiError::internal()
+        })
+}
+
+// Creates the user described by an already-validated account.
+// `email_verified` is for accounts whose address is vouched for otherwise,
+// e.g. created by an administrator.
+pub async fn create<C: ConnectionTrait>(
+    db: &C,
+    acc: NewAccount,
+    email_verified: bool,
+) -> ApiResult<user::Model> {
+    // Checked up front so the form can point at the offending field;
+    // the unique indexes still catch concurrent registrations below
+    if let Some(existing) = User::find()
+        .filter(
+            Condition::any()
+                .add(user::Column::Username.eq(&acc.username))
+                .add(user::Column::Email.eq(&acc.email)),
+        )
+        .one(db)
+        .await?
+    {
+        return Err(if existing.username == acc.username {
+            username_taken()
+        } else {
+            email_taken()
+        });
+    }
+
+    let hashed = hash_password(acc.password).await?;
+    let model = user::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        username: Set(acc.username),
+        first_name: Set(acc.first_name),
+        last_name: Set(acc.last_name),
+        email: Set(acc.email),
+        phone: Set(acc.phone),
+        organization: Set(acc.organization),
+        salt: Set(hashed.salt),
+        hashed_password: Set(hashed.hash),
+        disabled: Set(false),
+        email_verified: Set(email_verified),
+    };
+    match model.insert(db).await {
+        Ok(user) => {
+            info!("created account {} ({})", user.username, user.id);
+            Ok(user)
+        }
+        Err(e) => Err(match e.sql_err() {
+            Some(SqlErr::UniqueConstraintViolation(msg)) => {
+                debug!("lost registration race: {}", msg);
+                if msg.to_lowercase().contains("username") {
+                    username_taken()
+                } else {
+                    email_taken()
+                }
+            }
+            _ => e.into(),
+        }),
+    }
+}
+
+// Looks a user up by username, or by email if `name` contains an '@'
+pub async fn find_by_login<C: ConnectionTrait>(
+    db: &C,
+    name: &str,
+) -> Result<Option<user::Model>, DbErr> {
+    let name = name.trim();
+    if name.contains('@') {
+        User::find().filter(user::Column::Email.eq(name.to_lowercase()))
+    } else {
+        User::find().filter(user::Column::Username.eq(name))
+    }
+    .one(db)
+    .await
+}
+
+// Ends every session belonging to the user
+pub async fn revoke_sessions<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<u64, DbErr> {
+    let res = Cookie::delete_many()
+        .filter(cookie::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    debug!("revoked {} sessions of {}", res.rows_affected, user_id);
+    Ok(res.rows_affected)
+}
+
+// Replaces the password and logs the user out everywhere
+pub async fn set_password<C: ConnectionTrait>(
+    db: &C,
+    policy: &PasswordPolicy,
+    user: user::Model,
+    password: String,
+) -> ApiResult<()> {
+    validate_password(policy, &user, &password).await?;
+    let hashed = hash_password(password).await?;
+    store_password(db, user, hashed).await
+}
+
+async fn store_password<C: ConnectionTrait>(
+    db: &C,
+    user: user::Model,
+    hashed: HashedPassword,
+) -> ApiResult<()> {
+    let id = user.id;
+    let mut model: user::ActiveModel = user.into();
+    model.salt = Set(hashed.salt);
+    model.hashed_password = Set(hashed.hash);
+    model.update(db).await?;
+    revoke_sessions(db, id).await?;
+    info!("changed password of {}", id);
+    Ok(())
+}
+
+// Disabled users can't log in; disabling also ends their sessions
+pub async fn set_disabled<C: ConnectionTrait>(
+    db: &C,
+    user: user::Model,
+    disabled: bool,
+) -> Result<(), DbErr> {
+    let id = user.id;
+    let mut model: user::ActiveModel = user.into();
+    model.disabled = Set(disabled);
+    model.update(db).await?;
+    if disabled {
+        revoke_sessions(db, id).await?;
+    }
+    info!("{} {}", if disabled { "disabled" } else { "enabled" }, id);
+    Ok(())
+}
+
+const VERIFY_EMAIL: &str = "verify-email";
+
+// Mails the user a link for verifying their address. The token is bound
+// to the address, so changing it invalidates links sent earlier.
+pub async fn send_verification(
+    mailer: &Mailer,
+    signer: &TokenSigner,
+    user: &user::Model,
+    lifetime_hours: i64,
+) -> MailResult {
+    let token = signer.sign(
+        VERIFY_EMAIL,
+        user.id,
+        &user.email,
+        chrono::Duration::hours(lifetime_hours),
+    );
+    let link = mailer.link(&format!("/verify-email?token={}", token));
+    mailer
+        .send(
+            &user.email,
+            "Confirm your email address",
+            format!(
+                "Hello {},\n\n\
+                 please confirm your email address for FastRequest by opening\n\n\
+                 {}\n\n\
+                 The link expires in {} hours. If you didn't create an account,\n\
+                 you can ignore this message.\n",
+                user.first_name, link, lifetime_hours
+            ),
+        )
+        .await
+}
+
+fn token_error(e: TokenError) -> ApiError {
+    match e {
+        TokenError::Expired => {
+            ApiError::bad_request("expired_token", "this link has expired; request a new one")
+        }
+        TokenError::Malformed | TokenError::Invalid => {
+            ApiError::bad_request("invalid_token", "this link is invalid")
+        }
+    }
+}
+
+// Marks the address a verification token was issued for as verified
+pub async fn verify_email<C: ConnectionTrait>(
+    db: &C,
+    signer: &TokenSigner,
+    token: &str,
+) -> ApiResult<user::Model> {
+    let token = TokenSigner::parse(token).map_err(token_error)?;
+    let user = User::find_by_id(token.user_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| token_error(TokenError::Invalid))?;
+    signer
+        .verify(VERIFY_EMAIL, &token, &user.email)
+        .map_err(token_error)?;
+    if user.email_verified {
+        return Ok(user);
+    }
+    set_email_verified(db, user, true).await.map_err(Into::into)
+}
+
+pub async fn set_email_verified<C: ConnectionTrait>(
+    db: &C,
+    user: user::Model,
+    verified: bool,
+) -> Result<user::Model, DbErr> {
+    let mut model: user::ActiveModel = user.into();
+    model.email_verified = Set(verified);
+    let user = model.update(db).await?;
+    info!(
+        "marked email of {} as {}",
+        user.id,
+        if verified { "verified" } else { "unverified" }
+    );
+    Ok(user)
+}
+
+// Only this hash is stored, so a leaked table can't be used to reset passwords
+fn hash_reset_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Stores a new password reset token for the user and returns it. Spent
+// and expired tokens of the user are cleared out on the way.
+pub async fn create_reset_token<C: ConnectionTrait>(
+    db: &C,
+    user: &user::Model,
+    lifetime_minutes: i64,
+) -> Result<String, DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+    PasswordReset::delete_many()
+        .filter(password_reset::Column::UserId.eq(user.id))
+        .filter(
+            Condition::any()
+                .add(password_reset::Column::UsedDatetime.is_not_null())
+                .add(password_reset::Column::ExpiryDatetime.lte(now)),
+        )
+        .exec(db)
+        .await?;
+    let token = auth::random_token();
+    password_reset::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        token_hash: Set(hash_reset_token(&token)),
+        expiry_datetime: Set(now + chrono::Duration::minutes(lifetime_minutes)),
+        used_datetime: Set(None),
+    }
+    .insert(db)
+    .await?;
+    debug!("created password reset token for {}", user.id);
+    Ok(token)
+}
+
+pub async fn send_password_reset(
+    mailer: &Mailer,
+    user: &user::Model,
+    token: &str,
+    lifetime_minutes: i64,
+) -> MailResult {
+    let link = mailer.link(&format!("/reset-password?token={}", token));
+    mailer
+        .send(
+            &user.email,
+            "Reset your password",
+            format!(
+                "Hello {},\n\n\
+                 someone asked to reset the password of your FastRequest account\n\
+                 ({}). To choose a new password, open\n\n\
+                 {}\n\n\
+                 The link works once and expires in {} minutes. If you didn't ask\n\
+                 for this, you can ignore this message.\n",
+                user.first_name, user.username, link, lifetime_minutes
+            ),
+        )
+        .await
+}
+
+// Spends a reset token: sets the new password, ends every session of the
+// user and voids their other outstanding tokens. Since only the owner of
+// the address could have received the token, it also verifies the address.
+pub async fn reset_password(
+    db: &DatabaseConnection,
+    policy: &PasswordPolicy,
+    token: &str,
+    password: String,
+) -> ApiResult<()> {
+    let now = chrono::Utc::now().naive_utc();
+    let (reset, user) = match PasswordReset::find()
+        .filter(password_reset::Column::TokenHash.eq(hash_reset_token(token.trim())))
+        .find_also_related(User)
+        .one(db)
+        .await?
+    {
+        Some((reset, Some(user))) if reset.used_datetime.is_none() && !user.disabled => {
+            (reset, user)
+        }
+        _ => return Err(token_error(TokenError::Invalid)),
+    };
+    if reset.expiry_datetime <= now {
+        return Err(token_error(TokenError::Expired));
+    }
+    // Checked and hashed before the transaction, so it isn't held open
+    // while that work runs. A rejected password leaves the token unspent.
+    validate_password(policy, &user, &password).await?;
+    let hashed = hash_password(password).await?;
+    let txn = db.begin().await?;
+    // Conditional, so concurrent requests can't both spend the token
+    let spent = PasswordReset::update_many()
+        .col_expr(password_reset::Column::UsedDatetime, Expr::value(now))
+        .filter(password_reset::Column::UserId.eq(user.id))
+        .filter(password_reset::Column::UsedDatetime.is_null())
+        .exec(&txn)
+        .await?;
+    if spent.rows_affected == 0 {
+        return Err(token_error(TokenError::Invalid));
+    }
+    let user = if user.email_verified {
+        user
+    } else {
+        set_email_verified(&txn, user, true).await?
+    };
+    store_password(&txn, user, hashed).await?;
+    txn.commit().await?;
+    Ok(())
+}
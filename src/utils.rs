@@ -1,7 +1,5 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::env::VarError;
-
 pub fn pexi(s: &str) -> bool {
     std::path::Path::new(s).exists()
 }
@@ -15,13 +13,3 @@ pub fn erxit(s: &str) -> ! {
 pub fn erxits(s: String) -> ! {
     erxit(&s)
 }
-
-pub fn uw_nofile(r: Result<String, VarError>) -> String {
-    if let Ok(s) = r {
-        s
-    } else {
-        erxit(
-            "attempted to access non-existent file\ncheck configuration and environment variables",
-        );
-    }
-}
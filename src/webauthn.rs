@@ -0,0 +1,790 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Passkey (WebAuthn Level 2) registration and login. Only what passkeys
+// need is implemented: ES256 keys, user verification required, and no
+// attestation, so any authenticator the browser accepts can be registered.
+// Challenges are kept in memory until used or expired; like ratelimit,
+// they aren't shared between instances.
+
+use crate::api::{ApiError, ApiResult};
+use crate::entities::{prelude::*, user, webauthn_credential};
+use crate::security;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rocket::http::Status;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbErr, EntityTrait,
+    QueryFilter, QueryOrder, Set,
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const CHALLENGE_LEN: usize = 32;
+// How long a ceremony may take, from options to response
+pub const TIMEOUT: Duration = Duration::from_secs(300);
+// Bounds the memory unauthenticated login attempts can tie up; see also
+// PasskeyLoginLimits, which keeps one client from using it all
+const MAX_PENDING: usize = 10_000;
+const MAX_NAME_LEN: usize = 64;
+// COSE algorithm identifier of ECDSA with P-256 and SHA-256
+const ES256: i128 = -7;
+
+// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_DATA: u8 = 0x40;
+
+struct RelyingParty {
+    // Host of settings.url; credentials are scoped to it
+    id: String,
+    // What browsers report in clientDataJSON, e.g. "https://example.org"
+    origin: String,
+}
+
+impl RelyingParty {
+    fn from_url(url: &str) -> Result<Self, String> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| format!("settings.url {} has no scheme", url))?;
+        let scheme = scheme.to_ascii_lowercase();
+        let authority = rest
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let id = security::url_host(url).to_ascii_lowercase();
+        if id.is_empty() {
+            return Err(format!("settings.url {} has no host", url));
+        }
+        if id.parse::<IpAddr>().is_ok() {
+            return Err("passkeys need a domain name in settings.url, not an address".to_owned());
+        }
+        if scheme != "https" && id != "localhost" {
+            return Err("passkeys need an https:// settings.url".to_owned());
+        }
+        // Browsers leave out default ports
+        let origin = match authority.strip_suffix(":443") {
+            Some(a) if scheme == "https" => format!("https://{}", a),
+            _ => format!("{}://{}", scheme, authority),
+        };
+        Ok(RelyingParty { id, origin })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Ceremony {
+    Register(Uuid),
+    Login,
+}
+
+// Managed state. Without a usable settings.url, passkeys are unavailable.
+pub struct Passkeys {
+    rp: Option<RelyingParty>,
+    challenges: Mutex<HashMap<String, (Instant, Ceremony)>>,
+}
+
+impl Passkeys {
+    // `url` is settings.url; if unset, https://localhost:`port` is assumed,
+    // as for links in mail
+    pub fn new(url: Option<&str>, port: u16) -> Self {
+        let url = url.map_or_else(|| format!("https://localhost:{}", port), str::to_owned);
+        let rp = match RelyingParty::from_url(&url) {
+            Ok(rp) => {
+                debug!("passkey relying party {} at {}", rp.id, rp.origin);
+                Some(rp)
+            }
+            Err(e) => {
+                warn!("{}; passkey login is unavailable", e);
+                None
+            }
+        };
+        Passkeys {
+            rp,
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rp(&self) -> ApiResult<&RelyingParty> {
+        self.rp.as_ref().ok_or_else(|| {
+            ApiError::new(
+                Status::ServiceUnavailable,
+                "passkeys_unavailable",
+                "passkeys are not available on this server",
+            )
+        })
+    }
+
+    fn issue(&self, ceremony: Ceremony) -> ApiResult<String> {
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock().unwrap();
+        if challenges.len() >= MAX_PENDING {
+            challenges.retain(|_, (issued, _)| now.duration_since(*issued) < TIMEOUT);
+            if challenges.len() >= MAX_PENDING {
+                warn!("too many pending passkey ceremonies");
+                return Err(ApiError::new(
+                    Status::ServiceUnavailable,
+                    "busy",
+                    "too many passkey requests; try again later",
+                ));
+            }
+        }
+        let mut bytes = [0u8; CHALLENGE_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        let challenge = URL_SAFE_NO_PAD.encode(bytes);
+        challenges.insert(challenge.clone(), (now, ceremony));
+        Ok(challenge)
+    }
+
+    // Challenges are single use, whether or not the response checks out
+    fn take(&self, challenge: &str) -> Option<Ceremony> {
+        let (issued, ceremony) = self.challenges.lock().unwrap().remove(challenge)?;
+        (issued.elapsed() < TIMEOUT).then_some(ceremony)
+    }
+
+    // Checks clientDataJSON against this server and returns the ceremony
+    // its challenge was issued for
+    fn client_data(&self, raw: &[u8], kind: &str) -> Result<Ceremony, &'static str> {
+        let rp = self.rp.as_ref().ok_or("no relying party")?;
+        let data: ClientData = serde_json::from_slice(raw).map_err(|_| "malformed client data")?;
+        let ceremony = self
+            .take(&data.challenge)
+            .ok_or("unknown or expired challenge")?;
+        if data.kind != kind {
+            return Err("wrong ceremony type");
+        }
+        if data.origin != rp.origin || data.cross_origin {
+            return Err("wrong origin");
+        }
+        Ok(ceremony)
+    }
+
+    // PublicKeyCredentialCreationOptions for navigator.credentials.create()
+    pub async fn start_registration<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        user: &user::Model,
+    ) -> ApiResult<serde_json::Value> {
+        let rp = self.rp()?;
+        let existing = credentials(db, user.id).await?;
+        let challenge = self.issue(Ceremony::Register(user.id))?;
+        Ok(json!({
+            "publicKey": {
+                "challenge": challenge,
+                "rp": { "id": rp.id, "name": "FastRequest" },
+                "user": {
+                    "id": URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+                    "name": user.username,
+                    "displayName": format!("{} {}", user.first_name, user.last_name),
+                },
+                "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 as i64 }],
+                "timeout": TIMEOUT.as_millis() as u64,
+                "attestation": "none",
+                "authenticatorSelection": {
+                    "residentKey": "required",
+                    "requireResidentKey": true,
+                    "userVerification": "required",
+                },
+                "excludeCredentials": existing.iter().map(descriptor).collect::<Vec<_>>(),
+            }
+        }))
+    }
+
+    // Verifies the browser's response and stores the new credential
+    pub async fn finish_registration<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        user_id: Uuid,
+        name: &str,
+        cred: &RegistrationCredential,
+    ) -> ApiResult<webauthn_credential::Model> {
+        let rp = self.rp()?;
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(ApiError::bad_request(
+                "invalid_name",
+                format!("name must be between 1 and {} characters", MAX_NAME_LEN),
+            )
+            .on_field("name"));
+        }
+        let (credential_id, public_key, sign_count) =
+            self.verify_registration(rp, user_id, cred).map_err(|e| {
+                debug!("rejected passkey registration of {}: {}", user_id, e);
+                ApiError::bad_request("invalid_passkey", "the passkey could not be registered")
+            })?;
+        if WebauthnCredential::find()
+            .filter(webauthn_credential::Column::CredentialId.eq(credential_id.clone()))
+            .one(db)
+            .await?
+            .is_some()
+        {
+            return Err(ApiError::conflict(
+                "passkey_exists",
+                "this passkey is already registered",
+            ));
+        }
+        let transports: Vec<&str> = cred
+            .response
+            .transports
+            .iter()
+            .map(String::as_str)
+            .filter(|t| t.bytes().all(|b| b.is_ascii_lowercase() || b == b'-'))
+            .collect();
+        let model = webauthn_credential::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            user_id: Set(user_id),
+            credential_id: Set(credential_id),
+            public_key: Set(public_key),
+            sign_count: Set(sign_count as i64),
+            transports: Set(transports.join(",")),
+            name: Set(name.to_owned()),
+            created_datetime: Set(chrono::Utc::now().naive_utc()),
+            last_used_datetime: Set(None),
+        }
+        .insert(db)
+        .await?;
+        info!("registered passkey {} for {}", model.id, user_id);
+        Ok(model)
+    }
+
+    // Returns (credential id, COSE public key, sign count)
+    fn verify_registration(
+        &self,
+        rp: &RelyingParty,
+        user_id: Uuid,
+        cred: &RegistrationCredential,
+    ) -> Result<(Vec<u8>, Vec<u8>, u32), &'static str> {
+        let client_data = b64(&cred.response.client_data_json)?;
+        match self.client_data(&client_data, "webauthn.create")? {
+            Ceremony::Register(id) if id == user_id => {}
+            _ => return Err("challenge was issued for something else"),
+        }
+        // Attestation statements aren't checked; "none" was asked for
+        let attestation: Value =
+            ciborium::de::from_reader(b64(&cred.response.attestation_object)?.as_slice())
+                .map_err(|_| "malformed attestation object")?;
+        let auth_data = map_get(&attestation, &Value::from("authData"))
+            .and_then(Value::as_bytes)
+            .ok_or("attestation object has no authData")?;
+        let auth = AuthData::parse(auth_data)?;
+        auth.check(rp)?;
+        let (credential_id, public_key) = auth.attested.ok_or("no attested credential")?;
+        if credential_id != b64(&cred.raw_id)? {
+            return Err("credential id mismatch");
+        }
+        es256_key(&public_key)?;
+        Ok((credential_id, public_key, auth.sign_count))
+    }
+
+    // PublicKeyCredentialRequestOptions for navigator.credentials.get().
+    // allowCredentials is left empty, so the browser offers the passkeys it
+    // has for this site and no username is needed.
+    pub fn start_login(&self) -> ApiResult<serde_json::Value> {
+        let rp = self.rp()?;
+        let challenge = self.issue(Ceremony::Login)?;
+        Ok(json!({
+            "publicKey": {
+                "challenge": challenge,
+                "rpId": rp.id,
+                "timeout": TIMEOUT.as_millis() as u64,
+                "userVerification": "required",
+                "allowCredentials": [],
+            }
+        }))
+    }
+
+    // Verifies an assertion and returns the user it logs in. Since user
+    // verification is required, this counts as two factors by itself.
+    pub async fn finish_login<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        cred: &AssertionCredential,
+    ) -> ApiResult<user::Model> {
+        let rp = self.rp()?;
+        let rejected = |e: &str| {
+            debug!("rejected passkey login: {}", e);
+            ApiError::new(
+                Status::Unauthorized,
+                "invalid_passkey",
+                "the passkey could not be verified",
+            )
+        };
+        let client_data = b64(&cred.response.client_data_json).map_err(rejected)?;
+        if !matches!(
+            self.client_data(&client_data, "webauthn.get")
+                .map_err(rejected)?,
+            Ceremony::Login
+        ) {
+            return Err(rejected("challenge was issued for something else"));
+        }
+        let raw_id = b64(&cred.raw_id).map_err(rejected)?;
+        let (row, user) = match WebauthnCredential::find()
+            .filter(webauthn_credential::Column::CredentialId.eq(raw_id))
+            .find_also_related(User)
+            .one(db)
+            .await?
+        {
+            Some((row, Some(user))) => (row, user),
+            _ => return Err(rejected("unknown credential")),
+        };
+        if let Some(ref handle) = cred.response.user_handle {
+            if b64(handle).map_err(rejected)? != user.id.as_bytes() {
+                return Err(rejected("user handle mismatch"));
+            }
+        }
+        let auth_data = b64(&cred.response.authenticator_data).map_err(rejected)?;
+        let auth = AuthData::parse(&auth_data).map_err(rejected)?;
+        auth.check(rp).map_err(rejected)?;
+
+        let key = es256_key(&row.public_key).map_err(rejected)?;
+        let signature = Signature::from_der(&b64(&cred.response.signature).map_err(rejected)?)
+            .map_err(|_| rejected("malformed signature"))?;
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+        key.verify(&signed, &signature)
+            .map_err(|_| rejected("bad signature"))?;
+
+        let count = auth.sign_count as i64;
+        if !sign_count_ok(row.sign_count, count) {
+            warn!(
+                "passkey {} of {} reported sign count {} after {}; possibly cloned",
+                row.id, user.id, count, row.sign_count
+            );
+            return Err(rejected("sign count did not increase"));
+        }
+        // Conditional, so concurrent logins can't both pass with one count
+        let updated = WebauthnCredential::update_many()
+            .col_expr(webauthn_credential::Column::SignCount, Expr::value(count))
+            .col_expr(
+                webauthn_credential::Column::LastUsedDatetime,
+                Expr::value(chrono::Utc::now().naive_utc()),
+            )
+            .filter(webauthn_credential::Column::Id.eq(row.id))
+            .filter(webauthn_credential::Column::SignCount.eq(row.sign_count))
+            .exec(db)
+            .await?;
+        if updated.rows_affected == 0 {
+            return Err(rejected("concurrent use"));
+        }
+        Ok(user)
+    }
+}
+
+// Counters that don't go up hint at a cloned authenticator; synced
+// passkeys always report 0
+fn sign_count_ok(stored: i64, reported: i64) -> bool {
+    (stored == 0 && reported == 0) || reported > stored
+}
+
+// Helpers for the serialized PublicKeyCredential (its toJSON() form)
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationCredential {
+    #[serde(rename = "rawId")]
+    pub raw_id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionCredential {
+    #[serde(rename = "rawId")]
+    pub raw_id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+// base64url, with or without padding
+fn b64(s: &str) -> Result<Vec<u8>, &'static str> {
+    URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .map_err(|_| "malformed base64")
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+// Parses a COSE_Key, accepting only ES256 on P-256
+fn es256_key(cose: &[u8]) -> Result<VerifyingKey, &'static str> {
+    let key: Value = ciborium::de::from_reader(cose).map_err(|_| "malformed public key")?;
+    let int = |label: i64| map_get(&key, &Value::from(label));
+    let is = |label: i64, want: i128| {
+        int(label)
+            .and_then(Value::as_integer)
+            .is_some_and(|v| i128::from(v) == want)
+    };
+    // kty EC2, alg ES256, crv P-256
+    if !(is(1, 2) && is(3, ES256) && is(-1, 1)) {
+        return Err("unsupported key type");
+    }
+    let coord = |label: i64| {
+        int(label)
+            .and_then(Value::as_bytes)
+            .filter(|b| b.len() == 32)
+            .ok_or("malformed public key")
+    };
+    let mut sec1 = vec![0x04];
+    sec1.extend_from_slice(coord(-2)?);
+    sec1.extend_from_slice(coord(-3)?);
+    VerifyingKey::from_sec1_bytes(&sec1).map_err(|_| "invalid public key")
+}
+
+struct AuthData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    // Credential id and COSE public key, on registration
+    attested: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> AuthData<'a> {
+    // rpIdHash (32) | flags (1) | signCount (4) | attestedCredentialData?
+    fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < 37 {
+            return Err("authenticator data too short");
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+        let attested = if flags & ATTESTED_DATA != 0 {
+            // aaguid (16) | credentialIdLength (2) | credentialId | COSE_Key
+            let len = data
+                .get(53..55)
+                .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+                .ok_or("authenticator data too short")?;
+            let id = data
+                .get(55..55 + len)
+                .ok_or("authenticator data too short")?;
+            let rest = &data[55 + len..];
+            // Extensions may follow the key, so find where it ends
+            let mut cursor = std::io::Cursor::new(rest);
+            let _: Value =
+                ciborium::de::from_reader(&mut cursor).map_err(|_| "malformed public key")?;
+            let key = &rest[..cursor.position() as usize];
+            Some((id.to_vec(), key.to_vec()))
+        } else {
+            None
+        };
+        Ok(AuthData {
+            rp_id_hash: &data[..32],
+            flags,
+            sign_count,
+            attested,
+        })
+    }
+
+    fn check(&self, rp: &RelyingParty) -> Result<(), &'static str> {
+        if self.rp_id_hash != Sha256::digest(rp.id.as_bytes()).as_slice() {
+            return Err("wrong relying party");
+        }
+        if self.flags & USER_PRESENT == 0 || self.flags & USER_VERIFIED == 0 {
+            return Err("user not verified");
+        }
+        Ok(())
+    }
+}
+
+// PublicKeyCredentialDescriptor of a stored credential
+fn descriptor(cred: &webauthn_credential::Model) -> serde_json::Value {
+    let transports: Vec<&str> = cred
+        .transports
+        .split(',')
+        .filter(|t| !t.is_empty())
+        .collect();
+    json!({
+        "type": "public-key",
+        "id": URL_SAFE_NO_PAD.encode(&cred.credential_id),
+        "transports": transports,
+    })
+}
+
+pub async fn credentials<C: ConnectionTrait>(
+    db: &C,
+    user_id: Uuid,
+) -> Result<Vec<webauthn_credential::Model>, DbErr> {
+    WebauthnCredential::find()
+        .filter(webauthn_credential::Column::UserId.eq(user_id))
+        .order_by_asc(webauthn_credential::Column::CreatedDatetime)
+        .all(db)
+        .await
+}
+
+// Deletes one of the user's credentials; false if they have no such one
+pub async fn remove<C: ConnectionTrait>(db: &C, user_id: Uuid, id: Uuid) -> Result<bool, DbErr> {
+    let res = WebauthnCredential::delete_many()
+        .filter(webauthn_credential::Column::Id.eq(id))
+        .filter(webauthn_credential::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    if res.rows_affected > 0 {
+        info!("removed passkey {} of {}", id, user_id);
+    }
+    Ok(res.rows_affected > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    // Assertion authenticator data for rp id "localhost": SHA-256 of it,
+    // flags UP|UV, sign count 42
+    const ASSERTION_DATA: [u8; 37] = [
+        0x49, 0x96, 0x0d, 0xe5, 0x88, 0x0e, 0x8c, 0x68, 0x74, 0x34, 0x17, 0x0f, 0x64, 0x76, 0x60,
+        0x5b, 0x8f, 0xe4, 0xae, 0xb9, 0xa2, 0x86, 0x32, 0xc7, 0x99, 0x5c, 0xf3, 0xba, 0x83, 0x1d,
+        0x97, 0x63, 0x05, 0x00, 0x00, 0x00, 0x2a,
+    ];
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[0x11; 32]).unwrap()
+    }
+
+    fn cbor(value: &Value) -> Vec<u8> {
+        let mut out = vec![];
+        ciborium::ser::into_writer(value, &mut out).unwrap();
+        out
+    }
+
+    fn cose_key(alg: i64, x: &[u8], y: &[u8]) -> Vec<u8> {
+        cbor(&Value::Map(vec![
+            (Value::from(1i64), Value::from(2i64)),
+            (Value::from(3i64), Value::from(alg)),
+            (Value::from(-1i64), Value::from(1i64)),
+            (Value::from(-2i64), Value::Bytes(x.to_vec())),
+            (Value::from(-3i64), Value::Bytes(y.to_vec())),
+        ]))
+    }
+
+    fn es256_cose_key() -> Vec<u8> {
+        let point = signing_key().verifying_key().to_encoded_point(false);
+        cose_key(-7, point.x().unwrap(), point.y().unwrap())
+    }
+
+    // Registration authenticator data: aaguid (zeros), `id` and `key`,
+    // then `extensions` as authenticators append them
+    fn attested_data(flags: u8, id: &[u8], key: &[u8], extensions: &[u8]) -> Vec<u8> {
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        data.push(flags | ATTESTED_DATA);
+        data.extend_from_slice(&7u32.to_be_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&(id.len() as u16).to_be_bytes());
+        data.extend_from_slice(id);
+        data.extend_from_slice(key);
+        data.extend_from_slice(extensions);
+        data
+    }
+
+    fn rp(url: &str) -> RelyingParty {
+        RelyingParty::from_url(url).unwrap()
+    }
+
+    #[test]
+    fn parses_assertion_data() {
+        let auth = AuthData::parse(&ASSERTION_DATA).unwrap();
+        assert_eq!(auth.flags, USER_PRESENT | USER_VERIFIED);
+        assert_eq!(auth.sign_count, 42);
+        assert!(auth.attested.is_none());
+        assert!(auth.check(&rp("https://localhost:4433")).is_ok());
+        assert_eq!(
+            auth.check(&rp("https://example.org")),
+            Err("wrong relying party")
+        );
+        assert!(AuthData::parse(&ASSERTION_DATA[..36]).is_err());
+    }
+
+    #[test]
+    fn requires_user_verification() {
+        let localhost = rp("https://localhost");
+        for flags in [0, USER_PRESENT, USER_VERIFIED] {
+            let mut data = ASSERTION_DATA;
+            data[32] = flags;
+            let auth = AuthData::parse(&data).unwrap();
+            assert_eq!(auth.check(&localhost), Err("user not verified"));
+        }
+    }
+
+    #[test]
+    fn parses_attested_credential() {
+        let id = [0xab; 16];
+        let key = es256_cose_key();
+        let extensions = cbor(&Value::Map(vec![(
+            Value::from("credProtect"),
+            Value::from(2i64),
+        )]));
+        let data = attested_data(USER_PRESENT | USER_VERIFIED, &id, &key, &extensions);
+        let auth = AuthData::parse(&data).unwrap();
+        assert_eq!(auth.sign_count, 7);
+        let (parsed_id, parsed_key) = auth.attested.unwrap();
+        assert_eq!(parsed_id, id);
+        // Only the key, without the extensions after it
+        assert_eq!(parsed_key, key);
+
+        // Credential id running past the end, and a missing key
+        let truncated = &data[..55 + 8];
+        assert!(AuthData::parse(truncated).is_err());
+        let keyless = attested_data(USER_PRESENT, &id, &[], &[]);
+        assert!(AuthData::parse(&keyless).is_err());
+    }
+
+    #[test]
+    fn es256_keys() {
+        let key = es256_key(&es256_cose_key()).unwrap();
+        let message = b"authenticator data and client data hash";
+        let signature: Signature = signing_key().sign(message);
+        assert!(key.verify(message, &signature).is_ok());
+        assert!(key.verify(b"something else", &signature).is_err());
+
+        let point = signing_key().verifying_key().to_encoded_point(false);
+        let (x, y) = (point.x().unwrap(), point.y().unwrap());
+        // RS256 and EdDSA aren't supported
+        assert_eq!(
+            es256_key(&cose_key(-257, x, y)).err(),
+            Some("unsupported key type")
+        );
+        assert_eq!(
+            es256_key(&cose_key(-8, x, y)).err(),
+            Some("unsupported key type")
+        );
+        assert_eq!(
+            es256_key(&cose_key(-7, &x[1..], y)).err(),
+            Some("malformed public key")
+        );
+        // Not a point on the curve
+        assert_eq!(
+            es256_key(&cose_key(-7, &[0; 32], &[0; 32])).err(),
+            Some("invalid public key")
+        );
+        assert!(es256_key(b"\xff\x00garbage").is_err());
+    }
+
+    #[test]
+    fn relying_parties() {
+        let cases = [
+            ("https://example.org", "example.org", "https://example.org"),
+            (
+                "https://Example.ORG:443/app/",
+                "example.org",
+                "https://example.org",
+            ),
+            (
+                "https://example.org:8443",
+                "example.org",
+                "https://example.org:8443",
+            ),
+            (
+                "https://localhost:4433",
+                "localhost",
+                "https://localhost:4433",
+            ),
+            // Browsers treat localhost as secure without TLS
+            (
+                "http://localhost:8000",
+                "localhost",
+                "http://localhost:8000",
+            ),
+        ];
+        for (url, id, origin) in cases {
+            let rp = rp(url);
+            assert_eq!(rp.id, id, "{}", url);
+            assert_eq!(rp.origin, origin, "{}", url);
+        }
+        for url in [
+            "http://example.org",
+            "https://127.0.0.1:4433",
+            "https://[::]:4433",
+            "https://[::1]",
+            "example.org",
+            "https://",
+        ] {
+            assert!(RelyingParty::from_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn sign_counts() {
+        // Synced passkeys never count
+        assert!(sign_count_ok(0, 0));
+        assert!(sign_count_ok(0, 1));
+        assert!(sign_count_ok(5, 6));
+        assert!(sign_count_ok(5, 500));
+        assert!(!sign_count_ok(5, 5));
+        assert!(!sign_count_ok(5, 4));
+        // A counter that stops counting is as suspicious as one going back
+        assert!(!sign_count_ok(5, 0));
+    }
+
+    #[test]
+    fn challenges_are_single_use() {
+        let passkeys = Passkeys::new(Some("https://example.org"), 4433);
+        let challenge = passkeys.issue(Ceremony::Login).ok().unwrap();
+        let client_data = |kind: &str, origin: &str| {
+            serde_json::to_vec(&json!({
+                "type": kind,
+                "challenge": challenge,
+                "origin": origin,
+            }))
+            .unwrap()
+        };
+        let ok = client_data("webauthn.get", "https://example.org");
+        assert!(matches!(
+            passkeys.client_data(&ok, "webauthn.get"),
+            Ok(Ceremony::Login)
+        ));
+        assert!(passkeys.client_data(&ok, "webauthn.get").is_err());
+
+        // A wrong origin spends the challenge too
+        let challenge = passkeys.issue(Ceremony::Login).ok().unwrap();
+        let evil = serde_json::to_vec(&json!({
+            "type": "webauthn.get",
+            "challenge": challenge,
+            "origin": "https://evil.example",
+        }))
+        .unwrap();
+        assert_eq!(
+            passkeys.client_data(&evil, "webauthn.get").err(),
+            Some("wrong origin")
+        );
+        assert!(passkeys.take(&challenge).is_none());
+    }
+}